@@ -1,105 +1,360 @@
 use actix_web;
 use prometheus_client::encoding::EncodeLabelSet;
-use prometheus_client::metrics::family::Family;
-use prometheus_client::metrics::gauge::Gauge;
 use prometheus_client::registry::Registry;
+use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
 use tokio::sync::mpsc;
+use tokio::time::{interval, Duration};
 
 pub mod dell;
 pub mod hpe;
 pub mod lenovo;
+pub mod matching;
+pub mod metrics;
+pub mod traits;
 pub mod utils;
 
 use super::settings::Console;
 use super::settings::Settings;
-use super::netbox::Netbox;
+use super::netbox::{Inventory, Netbox};
 
-use dell::collect_dell_metrics;
-use hpe::collect_hpe_metrics;
-use lenovo::collect_lenovo_metrics;
+use matching::Matcher;
+use metrics::{CollectorLabels, ConsoleMetrics};
+use traits::{CollectError, Collector, Collectors, Stage};
 
-#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
-pub struct NodeLabels {
+/// Vendor modules the exporter creates collectors of, adding a vendor only takes an entry here.
+const VENDORS: [Collectors; 3] = [dell::collectors, lenovo::collectors, hpe::collectors];
+
+/// Health of a node as reported by its console, mapped from the vendor specific status.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Health {
+    Ok,
+    Warning,
+    Critical,
+    /// the console has no status for the node or cannot reach it
+    Unknown,
+}
+
+impl Health {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Health::Ok => "ok",
+            Health::Warning => "warning",
+            Health::Critical => "critical",
+            Health::Unknown => "unknown",
+        }
+    }
+}
+
+/// Firmware compliance of a node against the policy (baseline, profile template) of its console.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Hash)]
+pub enum ComplianceState {
+    Compliant,
+    NonCompliant,
+    /// compliance could not be determined, e.g. because the console call failed
+    NotEvaluated(String),
+    /// the console has no policy targeting the node
+    NoPolicy,
+}
+
+impl ComplianceState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ComplianceState::Compliant => "compliant",
+            ComplianceState::NonCompliant => "non_compliant",
+            ComplianceState::NotEvaluated(_) => "not_evaluated",
+            ComplianceState::NoPolicy => "no_policy",
+        }
+    }
+
+    pub fn reason(&self) -> &str {
+        match self {
+            ComplianceState::NotEvaluated(reason) => reason,
+            _ => "",
+        }
+    }
+
+    pub fn not_evaluated(reason: &str) -> ComplianceState {
+        ComplianceState::NotEvaluated(reason.to_string())
+    }
+}
+
+impl Default for ComplianceState {
+    fn default() -> ComplianceState {
+        ComplianceState::not_evaluated("not_checked")
+    }
+}
+
+/// A firmware component of a node, evaluated against the node's baseline.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Component {
     pub name: String,
+    pub current_version: String,
+    pub baseline_version: String,
+    pub compliant: bool,
 }
-#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Hash, EncodeLabelSet)]
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Hash)]
 pub struct Node {
     pub device_name: String,
     pub model: String,
-    pub health_status: u16,
-    pub connection_state: u8,
+    pub health: Health,
+    /// 1 if the console is connected to the node, `None` if the console can't tell
+    pub connection_state: Option<u8>,
     pub power_state: u16,
-    pub compliant: u8,
+    pub compliance: ComplianceState,
+    /// policy (baseline) the compliance was evaluated against
+    pub baseline: String,
+    /// components with a known compliance, only reported by some consoles
+    pub components: Vec<Component>,
     pub console: String,
+    pub console_host: String,
     pub uuid: String,
+    pub serial: String,
+    pub asset_tag: String,
+    /// labels of the matching netbox device
+    pub netbox_labels: Vec<(String, String)>,
 }
 
 impl Default for Node {
     fn default() -> Node {
         Node {
             console: "na".to_string(),
-            compliant: 0,
-            connection_state: 0,
+            console_host: "na".to_string(),
+            compliance: ComplianceState::default(),
+            baseline: "na".to_string(),
+            components: vec![],
+            connection_state: None,
             device_name: "na".to_string(),
-            health_status: 0,
+            health: Health::Unknown,
             model: "na".to_string(),
             power_state: 0,
             uuid: "na".to_string(),
+            serial: "na".to_string(),
+            asset_tag: "na".to_string(),
+            netbox_labels: vec![],
         }
     }
 }
 
+/// A netbox device which is missing on the console expected to manage it.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, EncodeLabelSet)]
+pub struct UnmanagedDevice {
+    pub device_name: String,
+    pub netbox_id: u64,
+    pub site: String,
+    pub console: String,
+}
+
+/// A console node without a netbox device, usually mis-named or undocumented.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, EncodeLabelSet)]
+pub struct OrphanedDevice {
+    pub console: String,
+    pub device_name: String,
+    pub model: String,
+    pub uuid: String,
+    pub serial: String,
+}
+
+/// A netbox device matching several console nodes, or a node claimed by several devices.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, EncodeLabelSet)]
+pub struct AmbiguousMatch {
+    pub console: String,
+    pub device_name: String,
+    pub netbox_id: u64,
+    pub candidates: String,
+}
+
+/// A netbox device found on another console than the one named by its console field.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, EncodeLabelSet)]
+pub struct MisassignedDevice {
+    pub console: String,
+    pub expected_console: String,
+    pub device_name: String,
+    pub netbox_id: u64,
+}
+
+/// All nodes reported by a console during one collect run.
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    pub console: String,
+    pub nodes: Vec<Node>,
+    pub unmanaged: Vec<UnmanagedDevice>,
+    pub orphaned: Vec<OrphanedDevice>,
+    pub ambiguous: Vec<AmbiguousMatch>,
+    pub misassigned: Vec<MisassignedDevice>,
+}
+
 #[derive(Debug)]
 pub struct Exporter {
-    //collectors: Vec<Box<dyn traits::Collector>>,
+    collectors: Vec<Arc<dyn Collector>>,
     settings: Settings,
-    nodes: Vec<String>,
+    snapshots: HashMap<String, Snapshot>,
     pub registry: Registry,
-    pub metrics: Family<Node, Gauge>,
+    pub metrics: ConsoleMetrics,
 }
 
 impl Exporter {
     pub fn new(s: Settings) -> Exporter {
-        Exporter {
-            settings: s,
-            nodes: vec![],
+        let mut exporter = Exporter {
+            collectors: vec![],
+            settings: s.clone(),
+            snapshots: HashMap::new(),
             registry: Registry::default(),
-            metrics: Family::default(),
+            metrics: ConsoleMetrics::default(),
+        };
+        let interval = Duration::from_secs(s.interval_in_min * 60);
+        for collectors in VENDORS {
+            for collector in collectors(&s, interval, &exporter.metrics) {
+                exporter.register(collector);
+            }
         }
+        exporter
     }
 
-    pub fn inc_requests(&self, node: Node) {
-        self.metrics
-            .get_or_create(&node)
-            .set(i64::from(node.compliant));
+    /// adds a collector which is spawned once the exporter runs
+    pub fn register(&mut self, collector: Arc<dyn Collector>) {
+        self.metrics.add_collector(CollectorLabels {
+            console: collector.name().to_string(),
+            console_host: collector.host().to_string(),
+            vendor: collector.vendor().to_string(),
+        });
+        self.collectors.push(collector);
     }
 
     pub async fn run(&mut self) {
-        let (tx, mut rx): (mpsc::Sender<Node>, mpsc::Receiver<Node>) = mpsc::channel(100);
-        let tx01 = tx.clone();
-        let tx02 = tx.clone();
-        let s = self.settings.clone();
-
-        let netbox = Netbox::new(s.netbox_url.to_owned(), s.query.to_owned());
-        let netbox_hpe = netbox.clone();
-        let netbox_lenovo = netbox.clone();
-        actix_web::rt::spawn(async move {
-            collect_dell_metrics(s.dell, netbox.clone(), s.interval_in_min, tx).await;
-        });
-        actix_web::rt::spawn(async move {
-            collect_lenovo_metrics(s.lenovo, netbox_lenovo, s.interval_in_min, tx01).await;
-        });
+        let (tx, mut rx): (mpsc::Sender<Snapshot>, mpsc::Receiver<Snapshot>) = mpsc::channel(100);
+        let netbox = Netbox::new(
+            self.settings.netbox_url.to_owned(),
+            self.settings.query.to_owned(),
+            &self.settings.netbox,
+        );
+        let refresh_interval = self.settings.netbox.refresh_interval_in_min.unwrap_or(self.settings.interval_in_min);
+        let inventory = Inventory::new(
+            netbox,
+            Duration::from_secs(refresh_interval * 60),
+            self.settings.netbox.cache_file.to_owned(),
+        );
+
+        if self.collectors.is_empty() {
+            warn!("no consoles configured, nothing to collect");
+        }
+        // consoles with the same filter share their netbox devices
+        let mut filters = self.collectors.iter()
+            .map(|c| c.netbox_query())
+            .collect::<Vec<String>>();
+        filters.sort();
+        filters.dedup();
+        let refresher = inventory.clone();
+        let metrics = self.metrics.clone();
         actix_web::rt::spawn(async move {
-            collect_hpe_metrics(s.hpe, netbox_hpe, s.interval_in_min, tx02).await;
+            run_inventory(refresher, filters, metrics).await;
         });
+        let consoles = Arc::new(self.collectors.iter().map(|c| c.name().to_string()).collect::<Vec<String>>());
+        for collector in self.collectors.iter() {
+            info!("starting {} collector {}", collector.vendor(), collector.name());
+            let collector = collector.clone();
+            let inventory = inventory.clone();
+            let consoles = consoles.clone();
+            let metrics = self.metrics.clone();
+            let tx = tx.clone();
+            actix_web::rt::spawn(async move {
+                run_collector(collector, inventory, consoles, metrics, tx).await;
+            });
+        }
+        drop(tx);
 
-        while let Some(n) = rx.recv().await {
-            println!("{:?}=>{:?}", n.console, n.device_name);
-            if !self.nodes.contains(&n.device_name) {
-                self.nodes.push(n.device_name.clone());
+        while let Some(snapshot) = rx.recv().await {
+            info!(
+                "{} reported {} nodes, {} unmanaged, {} orphaned, {} ambiguous, {} misassigned",
+                snapshot.console,
+                snapshot.nodes.len(),
+                snapshot.unmanaged.len(),
+                snapshot.orphaned.len(),
+                snapshot.ambiguous.len(),
+                snapshot.misassigned.len()
+            );
+            let previous = self.snapshots.remove(&snapshot.console).unwrap_or_default();
+            self.metrics.replace_snapshot(&previous, &snapshot);
+            self.snapshots.insert(snapshot.console.to_string(), snapshot);
+        }
+    }
+}
+
+/// Refreshes the netbox devices of all console filters on the inventory's interval.
+/// Failed refreshes keep the previous devices, collectors keep matching against them.
+async fn run_inventory(inventory: Inventory, filters: Vec<String>, metrics: ConsoleMetrics) {
+    let mut interval = interval(inventory.interval());
+
+    loop {
+        interval.tick().await;
+        for filter in filters.iter() {
+            if let Err(e) = inventory.refresh(filter).await {
+                if let Some(reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN) = e.status() {
+                    metrics.netbox_auth_errors.inc();
+                }
+                error!("refreshing netbox devices of {}: {}", filter, e);
             }
-            self.inc_requests(n);
         }
     }
 }
+
+async fn run_collector(
+    collector: Arc<dyn Collector>,
+    inventory: Inventory,
+    consoles: Arc<Vec<String>>,
+    metrics: ConsoleMetrics,
+    tx: mpsc::Sender<Snapshot>,
+) {
+    let mut interval = interval(collector.interval());
+
+    loop {
+        interval.tick().await;
+        let start = Instant::now();
+        let mut errors = vec![];
+        match collect_snapshot(collector.as_ref(), &inventory, &consoles, &metrics, &mut errors).await {
+            Ok(snapshot) => tx.send(snapshot).await.unwrap(),
+            Err(e) => errors.push(e),
+        }
+        for e in errors.iter() {
+            error!("{} collect: {}", collector.name(), e);
+        }
+        metrics.observe_collect(collector.name(), start.elapsed(), &errors);
+    }
+}
+
+/// Runs the collector once and matches its nodes against the netbox inventory. If
+/// either fails no snapshot is returned, so the previous one stays exported. A failed
+/// netbox refresh is reported as error while the cached devices are still matched.
+async fn collect_snapshot(
+    collector: &dyn Collector,
+    inventory: &Inventory,
+    consoles: &[String],
+    metrics: &ConsoleMetrics,
+    errors: &mut Vec<CollectError>,
+) -> Result<Snapshot, CollectError> {
+    let nodes = collector.collect(errors).await?;
+
+    let filter = collector.netbox_query();
+    let netbox_devices = inventory.devices(&filter).await
+        .map_err(|e| {
+            if let Some(reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN) = e.status() {
+                metrics.netbox_auth_errors.inc();
+            }
+            CollectError::new(Stage::Netbox, e)
+        })?;
+    if let Some(e) = inventory.failure(&filter).await {
+        errors.push(CollectError::new(Stage::Netbox, format!("refreshing devices: {}", e)));
+    }
+    let age = netbox_devices.age();
+    if age > inventory.interval() {
+        warn!("{} matched against a netbox inventory from {}s ago", collector.name(), age.as_secs());
+    }
+    let devices = &netbox_devices.devices;
+    metrics.observe_netbox(collector.name(), devices.count, devices.results.len(), age);
+
+    Ok(Matcher::new(collector.console(), consoles).reconcile(nodes, devices.results.clone()))
+}
@@ -1,18 +1,31 @@
-use log::{error, info};
+use async_trait::async_trait;
+use log::{info, warn};
 use reqwest;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc;
-use tokio::time::{sleep, Duration, interval};
+use std::sync::Arc;
+use time::{format_description, PrimitiveDateTime};
+use tokio::time::{sleep, Duration, Instant};
 
-use crate::exporter::utils::{get_request_builder, deserialize_name};
+use crate::exporter::utils::get_request_builder;
+use crate::settings::{Settings, DELL_CONSOLE};
 
+use super::metrics::ConsoleMetrics;
+use super::traits::{CollectError, Collector, Stage};
 use super::Console;
-use super::Node;
-use super::Netbox;
+use super::{ComplianceState, Component, Health, Node};
+
+/// Header carrying the session token, both in the login response and in requests.
+const AUTH_TOKEN_HEADER: &str = "X-Auth-Token";
+
+/// Members requested per page of an OData collection.
+const PAGE_SIZE: u32 = 1000;
+
+/// Time between two status requests of a running compliance job.
+const JOB_POLL_INTERVAL: Duration = Duration::from_secs(5);
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Device {
-    #[serde(deserialize_with = "deserialize_name")]
     #[serde(alias = "DeviceName")]
     pub device_name: String,
     #[serde(alias = "Model", alias = "model")]
@@ -23,58 +36,65 @@ pub struct Device {
     pub connection_state: bool,
     #[serde(alias = "PowerState")]
     pub power_state: u16,
-    #[serde(alias = "Id")]
-    pub id: u16,
+    #[serde(rename = "DeviceServiceTag", default)]
+    pub service_tag: Option<String>,
+    #[serde(rename = "AssetTag", default)]
+    pub asset_tag: Option<String>,
 }
 
 impl From<Device> for Node {
     fn from(d: Device) -> Self {
-        let status = if d.status == 1000 { 1 } else { 0 }; //1000: normal, 3000: warning, 4000:critical
+        let health = match d.status {
+            _ if !d.connection_state => Health::Unknown,
+            1000 => Health::Ok,
+            3000 => Health::Warning,
+            4000 => Health::Critical,
+            _ => Health::Unknown, //2000: unknown, 5000: no status
+        };
         let power = if d.power_state == 17 { 1 } else { 0 };
         let connection = if d.connection_state { 1 } else { 0 };
-        let name = d.device_name.split(".").collect::<Vec<&str>>();
-        let name = name[0].to_string().replace("r", "");
         Self {
-            device_name: name,
-            health_status: status,
+            device_name: d.device_name,
+            health,
             model: d.model,
             power_state: power,
-            connection_state: connection,
-            compliant: 0,
+            connection_state: Some(connection),
+            compliance: ComplianceState::default(),
+            baseline: "na".to_string(),
+            components: vec![],
             console: "na".to_string(),
-            uuid: d.id.to_string(),
+            console_host: "na".to_string(),
+            // the device list has no system uuid, the device id is no replacement for it
+            uuid: "na".to_string(),
+            serial: d.service_tag.unwrap_or_else(|| "na".to_string()),
+            asset_tag: d.asset_tag.filter(|t| !t.is_empty()).unwrap_or_else(|| "na".to_string()),
+            netbox_labels: vec![],
         }
     }
 }
 
+/// A page of an OData collection, `next_link` points to the following one.
 #[derive(Serialize, Deserialize, Debug, Clone)]
-struct APIResponse {
-    #[serde(alias = "value")]
-    value: Vec<Device>,
+struct Page<T> {
+    value: Vec<T>,
+    #[serde(rename = "@odata.nextLink")]
+    next_link: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct Compliance {
     #[serde(rename = "Id")]
-    id: u16,
+    id: u64,
     #[serde(rename = "TaskId")]
-    task_id : u16,
+    task_id: u64,
     #[serde(rename = "Name")]
     name: String,
     #[serde(rename = "RepositoryName")]
     repository_name: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct CompliancePolicy {
-    value: Vec<Compliance>,
-}
-
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct ComplianceReport {
-    #[serde(rename = "DeviceId")]
-    id: u16,
-    #[serde(deserialize_with = "deserialize_name")]
     #[serde(rename = "DeviceName")] 
     name: String,
     #[serde(rename = "DeviceModel")] 
@@ -83,134 +103,563 @@ struct ComplianceReport {
     firmware_status: String,
     #[serde(rename = "ComplianceStatus")]
     compliance_status: String,
+    #[serde(rename = "ComponentComplianceReports", default)]
+    components: Vec<ComponentReport>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-struct ComplianceReports {
-    #[serde(alias = "value")]
-    value: Vec<ComplianceReport>,
+struct ComponentReport {
+    #[serde(rename = "Name")]
+    name: String,
+    #[serde(rename = "CurrentVersion", default)]
+    current_version: Option<String>,
+    /// version of the baseline's catalog
+    #[serde(rename = "Version", default)]
+    version: Option<String>,
+    #[serde(rename = "ComplianceStatus")]
+    compliance_status: String,
+}
+
+/// Maps the OME compliance status of a device or component, `None` if unknown.
+fn compliant(status: &str) -> Option<bool> {
+    match status {
+        "OK" => Some(true),
+        "UPGRADE" | "DOWNGRADE" | "WARNING" | "CRITICAL" => Some(false),
+        _ => None,
+    }
 }
 
-pub async fn collect_dell_metrics(settings: Console, netbox: Netbox, interval_sec: u64, tx: mpsc::Sender<Node>) {
-    info!("dell client ready. interval: {}", interval_sec);
-    let mut interval = interval(Duration::from_secs(interval_sec * 60));
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Job {
+    #[serde(rename = "LastRun")]
+    last_run: Option<String>,
+    #[serde(rename = "LastRunStatus")]
+    last_run_status: JobStatus,
+}
+
+impl Job {
+    /// Whether a run started after `previous_run` is done, right after starting
+    /// the job it still reports the previous run.
+    fn done_after(&self, previous_run: &Option<String>) -> bool {
+        self.last_run != *previous_run && JobState::from_status(&self.last_run_status).is_some()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct JobStatus {
+    #[serde(rename = "Id")]
+    id: u16,
+    #[serde(rename = "Name")]
+    name: String,
+}
+
+/// Final state of a compliance job, as far as the collector waited for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    Completed,
+    /// completed, but some devices could not be checked
+    Warning,
+    Failed,
+    /// aborted, stopped, paused or canceled
+    Aborted,
+    /// did not finish within the console's `job_timeout_in_sec`
+    Timeout,
+}
+
+impl JobState {
+    pub const ALL: [JobState; 5] = [
+        JobState::Completed,
+        JobState::Warning,
+        JobState::Failed,
+        JobState::Aborted,
+        JobState::Timeout,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobState::Completed => "completed",
+            JobState::Warning => "warning",
+            JobState::Failed => "failed",
+            JobState::Aborted => "aborted",
+            JobState::Timeout => "timeout",
+        }
+    }
+
+    /// Maps the OME job status ids, `None` for jobs which are not done yet.
+    fn from_status(status: &JobStatus) -> Option<JobState> {
+        match status.id {
+            2060 => Some(JobState::Completed),
+            2090 => Some(JobState::Warning),
+            2070 => Some(JobState::Failed),
+            2100..=2103 => Some(JobState::Aborted),
+            _ => None,
+        }
+    }
+}
 
-    loop {
-        interval.tick().await;
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SessionResponse {
+    #[serde(rename = "Id")]
+    id: String,
+}
+
+/// OME api session, its token authenticates all requests of a collect run.
+#[derive(Debug, Clone)]
+struct Session {
+    id: String,
+    token: String,
+}
+
+impl Session {
+    fn request(&self, method: reqwest::Method, url: reqwest::Url) -> reqwest::RequestBuilder {
+        get_request_builder(method, None, None, url).header(AUTH_TOKEN_HEADER, self.token.as_str())
+    }
+
+    /// Returns all members of the OData collection at `url`, following the
+    /// `@odata.nextLink` of each page.
+    async fn get_all<T: DeserializeOwned>(&self, mut url: reqwest::Url) -> Result<Vec<T>, reqwest::Error> {
+        let query = match url.query() {
+            Some(query) => format!("{}&$top={}", query, PAGE_SIZE),
+            None => format!("$top={}", PAGE_SIZE),
+        };
+        url.set_query(Some(query.as_str()));
+        let mut members = vec![];
+        let mut next = Some(url);
+        while let Some(url) = next {
+            let page = self.request(reqwest::Method::GET, url.clone())
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<Page<T>>()
+                .await?;
+            // an empty page ends the walk, even if a next one is linked
+            next = match page.next_link.filter(|_| !page.value.is_empty()) {
+                Some(link) => match url.join(&link) {
+                    Ok(next) => Some(next),
+                    Err(e) => {
+                        warn!("invalid next link {}: {}", link, e);
+                        None
+                    }
+                },
+                None => None,
+            };
+            members.extend(page.value);
+        }
+        Ok(members)
+    }
+}
+
+/// Outcome of running the compliance check of the console's baseline.
+enum ComplianceCheck {
+    Reports(Vec<ComplianceReport>),
+    BaselineNotFound,
+    /// the job did not complete, its reports may be outdated or half written
+    Incomplete(JobState),
+}
+
+#[derive(Debug)]
+pub struct DellCollector {
+    settings: Console,
+    interval: Duration,
+    metrics: ConsoleMetrics,
+}
+
+impl DellCollector {
+    pub fn new(settings: Console, interval: Duration, metrics: ConsoleMetrics) -> DellCollector {
+        info!("dell client {} ready. interval: {:?}", settings.name, interval);
+        DellCollector { settings, interval, metrics }
+    }
+}
+
+/// Returns a collector per configured openmanage console.
+pub fn collectors(settings: &Settings, interval: Duration, metrics: &ConsoleMetrics) -> Vec<Arc<dyn Collector>> {
+    settings.dell.iter()
+        .flat_map(|v| v.consoles(DELL_CONSOLE))
+        .map(|c| Arc::new(DellCollector::new(c, interval, metrics.clone())) as Arc<dyn Collector>)
+        .collect()
+}
+
+#[async_trait]
+impl Collector for DellCollector {
+    fn console(&self) -> &Console {
+        &self.settings
+    }
+
+    fn vendor(&self) -> &'static str {
+        "dell"
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    async fn collect(&self, errors: &mut Vec<CollectError>) -> Result<Vec<Node>, CollectError> {
         info!("executing dell metric collect");
 
-        let comliant_devices = get_compliant_devices(settings.clone()).await.unwrap_or_else(|e| {
-            error!("error getting compliant nodes: {}", e);
-            ComplianceReports{value: vec![]}
-        });
-
-        let devices = get_devices(settings.clone()).await.unwrap_or_else(|e| {
-            error!("error getting compliant nodes: {}", e);
-            vec![]
-        });
-
-        let mut nodes = devices.clone().into_iter().map(|d| Node::from(d)).collect::<Vec<Node>>();
-        comliant_devices.value.iter().for_each(|c|{
-            nodes.iter_mut()
-                .find(|n| c.name == n.device_name)
-                .map(|n| if c.compliance_status == "OK" {n.compliant = 1});
-        });
-
-        let netbox_devices = netbox.get_devices_by_manufacturer(settings.manufacturer_name.to_string()).await 
-        .unwrap_or_else(|e| {
-            error!("error getting netbox devices: {}", e);
-            vec![]
-        });
-
-        for device in netbox_devices {
-            let node = nodes.iter_mut()
-                .find(|n| device.name.to_lowercase().contains(n.device_name.to_lowercase().as_str()));
-            if node.is_some() {
-                let n = node.unwrap();
-                n.console = "openmanage".to_string();
-                tx.send(n.clone()).await.unwrap();
-            } else {
-                let n = Node { device_name: device.name, ..Default::default() };
-                tx.send(n.clone()).await.unwrap();
+        let session = create_session(&self.settings).await?;
+
+        let checks = check_baselines(&self.settings, &session, &self.metrics).await;
+        let devices = get_devices(&self.settings, &session).await;
+
+        delete_session(&self.settings, &session)
+            .await
+            .unwrap_or_else(|e| {
+                errors.push(CollectError::new(Stage::Login, format!("deleting session: {}", e)));
+            });
+
+        let devices = devices.map_err(|e| CollectError::new(Stage::Devices, e))?;
+
+        let mut nodes = devices.into_iter().map(Node::from).collect::<Vec<Node>>();
+        let mut reports = vec![];
+        // nodes not targeted by any checked baseline may be targeted by an unchecked one
+        let mut unchecked = None;
+        match checks {
+            Ok(checks) => for (baseline, check) in checks {
+                match check {
+                    Ok(ComplianceCheck::Reports(r)) => reports.push((baseline, r)),
+                    Ok(ComplianceCheck::BaselineNotFound) => {
+                        errors.push(CollectError::new(Stage::Compliance, format!("baseline {} not found", baseline)));
+                        unchecked.get_or_insert("baseline_not_found");
+                    }
+                    Ok(ComplianceCheck::Incomplete(state)) => {
+                        errors.push(CollectError::new(
+                            Stage::Compliance,
+                            format!("compliance job of baseline {}: {}", baseline, state.as_str()),
+                        ));
+                        unchecked.get_or_insert(if state == JobState::Timeout { "job_timeout" } else { "job_failed" });
+                    }
+                    Err(e) => {
+                        errors.push(CollectError::new(Stage::Compliance, format!("checking baseline {}: {}", baseline, e)));
+                        unchecked.get_or_insert("request_failed");
+                    }
+                }
+            },
+            Err(e) => {
+                errors.push(CollectError::new(Stage::Compliance, e));
+                unchecked = Some("request_failed");
             }
         }
+        set_compliance(&mut nodes, &reports, unchecked);
+        Ok(nodes)
     }
 }
 
-async fn get_devices(settings: Console) -> Result<Vec<Device>, reqwest::Error>{
+/// Sets the compliance of each node from the report of the first baseline targeting it.
+/// Nodes without a report are not evaluated, with the `unchecked` reason, if a baseline
+/// could not be checked, as it may target them.
+fn set_compliance(nodes: &mut [Node], reports: &[(String, Vec<ComplianceReport>)], unchecked: Option<&str>) {
+    for n in nodes.iter_mut() {
+        let report = reports
+            .iter()
+            .find_map(|(baseline, r)| r.iter().find(|c| c.name == n.device_name).map(|c| (baseline, c)));
+        n.compliance = match (report, unchecked) {
+            (Some((baseline, c)), _) => {
+                n.baseline = baseline.to_string();
+                n.components = c.components
+                    .iter()
+                    .filter_map(|component| compliant(&component.compliance_status).map(|compliant| Component {
+                        name: component.name.to_string(),
+                        current_version: component.current_version.clone().unwrap_or_else(|| "na".to_string()),
+                        baseline_version: component.version.clone().unwrap_or_else(|| "na".to_string()),
+                        compliant,
+                    }))
+                    .collect();
+                match compliant(&c.compliance_status) {
+                    Some(true) => ComplianceState::Compliant,
+                    Some(false) => ComplianceState::NonCompliant,
+                    None => ComplianceState::not_evaluated("unknown_status"),
+                }
+            }
+            (None, Some(reason)) => ComplianceState::not_evaluated(reason),
+            // no baseline targets the device
+            (None, None) => ComplianceState::NoPolicy,
+        };
+    }
+}
+
+async fn get_devices(settings: &Console, session: &Session) -> Result<Vec<Device>, reqwest::Error>{
     let mut host = settings.host.clone();
     host.set_path("/api/DeviceService/Devices");
-    host.set_query(Some(("top=5000")));
-    let resp = get_request_builder(
-        reqwest::Method::GET,
-        None,
-        Some(&settings), 
-        host
-    )
-    .send()
-    .await?
-    .error_for_status()?
-    .json::<APIResponse>()
-    .await?;
-
-    Ok(resp.value)
+    session.get_all::<Device>(host).await
 }
 
-async fn get_compliant_devices(settings: Console) -> Result<ComplianceReports, reqwest::Error>{
+/// Runs the compliance checks of the console's baselines in their configured order.
+/// Fails if the baselines can't be listed, errors of a single check only fail the check.
+async fn check_baselines(
+    settings: &Console,
+    session: &Session,
+    metrics: &ConsoleMetrics,
+) -> Result<Vec<(String, Result<ComplianceCheck, reqwest::Error>)>, reqwest::Error> {
     let mut host = settings.host.clone();
     host.set_path("/api/UpdateService/Baselines");
 
-    let json = get_request_builder(
-        reqwest::Method::GET, 
-        None, 
-        Some(&settings), 
-        host.clone()
-    )
+    let baselines = session.get_all::<Compliance>(host).await?;
+
+    let mut checks = vec![];
+    for name in settings.baselines.iter() {
+        // baseline names are unique per console
+        let check = match baselines.iter().find(|b| &b.name == name) {
+            Some(baseline) => get_compliant_devices(settings, session, baseline, metrics).await,
+            None => Ok(ComplianceCheck::BaselineNotFound),
+        };
+        checks.push((name.to_string(), check));
+    }
+    Ok(checks)
+}
+
+/// Runs the compliance check of the baseline, waits for it to finish and returns its reports.
+async fn get_compliant_devices(
+    settings: &Console,
+    session: &Session,
+    baseline: &Compliance,
+    metrics: &ConsoleMetrics,
+) -> Result<ComplianceCheck, reqwest::Error>{
+    let previous = get_job(settings, session, baseline.task_id).await?;
+    let mut host = settings.host.clone();
+    host.set_path("/api/JobService/Actions/JobService.RunJobs");
+
+    session.request(reqwest::Method::POST, host)
+        .json(&serde_json::json!({"JobIds": [baseline.task_id], "AllJobs":false}))
+        .send()
+        .await?
+        .error_for_status()?;
+
+    info!("compliance check of baseline {} started", baseline.name);
+    let start = Instant::now();
+    let job = wait_for_job(settings, session, baseline.task_id, previous.last_run).await?;
+    let state = job.as_ref().and_then(|j| JobState::from_status(&j.last_run_status)).unwrap_or(JobState::Timeout);
+    info!("compliance check of baseline {} finished: {}", baseline.name, state.as_str());
+    if !matches!(state, JobState::Completed | JobState::Warning) {
+        metrics.observe_compliance_job(&settings.name, &baseline.name, state, start.elapsed(), None);
+        return Ok(ComplianceCheck::Incomplete(state));
+    }
+    let last_run = job.and_then(|j| j.last_run).and_then(|r| parse_timestamp(&r));
+    metrics.observe_compliance_job(&settings.name, &baseline.name, state, start.elapsed(), last_run);
+
+    let mut host = settings.host.clone();
+    host.set_path(format!("/api/UpdateService/Baselines({})/DeviceComplianceReports", baseline.id).as_str());
+    host.set_query(Some("$expand=ComponentComplianceReports"));
+    let reports = session.get_all::<ComplianceReport>(host).await?;
+    Ok(ComplianceCheck::Reports(reports))
+}
+
+async fn get_job(settings: &Console, session: &Session, id: u64) -> Result<Job, reqwest::Error> {
+    let mut host = settings.host.clone();
+    host.set_path(format!("/api/JobService/Jobs({})", id).as_str());
+    session.request(reqwest::Method::GET, host)
         .send()
         .await?
         .error_for_status()?
-        .json::<CompliancePolicy>()
-        .await?;
-
-    let task = json.value.iter().find(|v| v.repository_name == settings.policy_name && v.name == settings.policy_name);
-    match task {
-        Some(t) => {
-            let mut host = settings.host.clone();
-            host.set_path("/api/JobService/Actions/JobService.RunJobs");
-
-            get_request_builder(
-                reqwest::Method::POST,
-                None,
-                Some(&settings), 
-                host.clone()
-            )
-                .json(&serde_json::json!({"JobIds": [t.task_id], "AllJobs":false}))
-                .send()
-                .await?
-                .error_for_status()?;
-   
-            info!("compliance check started");
-            sleep(Duration::from_secs(20)).await;
-            info!("compliance check finished");
-            let mut host = settings.host.clone();
-            host.set_path(format!("/api/UpdateService/Baselines({})/DeviceComplianceReports", t.id).as_str());
-
-            let json = get_request_builder(
-                reqwest::Method::GET, 
-                None, 
-                Some(&settings), 
-                host.clone()
-            )
-                .send()
-                .await?
-                .error_for_status()?
-                .json::<ComplianceReports>()
-                .await?;
-            return Ok(json);
+        .json::<Job>()
+        .await
+}
+
+/// Polls the job until a run started after `previous_run` is done, `None` if it
+/// is not done within the console's job timeout.
+async fn wait_for_job(
+    settings: &Console,
+    session: &Session,
+    id: u64,
+    previous_run: Option<String>,
+) -> Result<Option<Job>, reqwest::Error> {
+    let deadline = Instant::now() + Duration::from_secs(settings.job_timeout_in_sec);
+    while Instant::now() < deadline {
+        sleep(JOB_POLL_INTERVAL).await;
+        let job = get_job(settings, session, id).await?;
+        if job.done_after(&previous_run) {
+            return Ok(Some(job));
         }
-        None => {
-            error!("no compliance task found");
-            return Ok(ComplianceReports{value: vec![]})
+        info!("compliance job {} is {}", id, job.last_run_status.name);
+    }
+    Ok(None)
+}
+
+async fn create_session(settings: &Console) -> Result<Session, CollectError> {
+    let mut host = settings.host.clone();
+    host.set_path("/api/SessionService/Sessions");
+    let body = serde_json::json!({
+        "UserName": settings.username,
+        "Password": settings.password,
+        "SessionType": "API",
+    });
+    let resp = get_request_builder(
+        reqwest::Method::POST,
+        None,
+        None,
+        host
+    )
+        .json(&body)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| CollectError::new(Stage::Login, e))?;
+    let token = resp.headers()
+        .get(AUTH_TOKEN_HEADER)
+        .and_then(|t| t.to_str().ok())
+        .map(|t| t.to_string())
+        .ok_or_else(|| CollectError::new(Stage::Login, "session created without a token"))?;
+    let id = resp.json::<SessionResponse>()
+        .await
+        .map_err(|e| CollectError::new(Stage::Login, e))?
+        .id;
+    Ok(Session { id, token })
+}
+
+async fn delete_session(settings: &Console, session: &Session) -> Result<(), reqwest::Error> {
+    let mut host = settings.host.clone();
+    host.set_path(format!("/api/SessionService/Sessions('{}')", session.id).as_str());
+    session.request(reqwest::Method::DELETE, host)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+/// Parses OME timestamps like `2023-05-10 09:04:21.123`, which are in UTC. The
+/// fraction is cut off, runtime parsed format descriptions can't make it optional.
+fn parse_timestamp(timestamp: &str) -> Option<f64> {
+    let format = format_description::parse("[year]-[month]-[day] [hour]:[minute]:[second]").ok()?;
+    let seconds = timestamp.split('.').next().unwrap_or_default();
+    PrimitiveDateTime::parse(seconds, &format)
+        .ok()
+        .map(|t| t.assume_utc().unix_timestamp() as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockServer;
+
+    #[test]
+    fn parses_ome_timestamps() {
+        assert_eq!(parse_timestamp("2023-05-10 09:04:21.123"), Some(1683709461.0));
+        assert_eq!(parse_timestamp("2023-05-10 09:04:21"), Some(1683709461.0));
+        assert_eq!(parse_timestamp(""), None);
+        assert_eq!(parse_timestamp("10.05.2023 09:04"), None);
+    }
+
+    fn report(name: &str, status: &str, components: serde_json::Value) -> ComplianceReport {
+        serde_json::from_value(serde_json::json!({
+            "DeviceName": name,
+            "DeviceModel": "PowerEdge R650",
+            "FirmwareStatus": "",
+            "ComplianceStatus": status,
+            "ComponentComplianceReports": components,
+        }))
+        .unwrap()
+    }
+
+    fn nodes(names: &[&str]) -> Vec<Node> {
+        names.iter().map(|n| Node { device_name: n.to_string(), ..Default::default() }).collect()
+    }
+
+    fn job(last_run: Option<&str>, status: u16) -> Job {
+        Job {
+            last_run: last_run.map(|r| r.to_string()),
+            last_run_status: JobStatus { id: status, name: "status".to_string() },
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn job_states_of_ome_status_ids() {
+        let state = |id| JobState::from_status(&JobStatus { id, name: "status".to_string() });
+        assert_eq!(state(2060), Some(JobState::Completed));
+        assert_eq!(state(2090), Some(JobState::Warning));
+        assert_eq!(state(2070), Some(JobState::Failed));
+        assert_eq!(state(2101), Some(JobState::Aborted));
+        // new, scheduled and running
+        assert_eq!(state(2080), None);
+        assert_eq!(state(2040), None);
+        assert_eq!(state(2050), None);
+    }
+
+    #[test]
+    fn job_is_done_after_a_new_finished_run() {
+        let previous = Some("2023-05-10 09:04:21.123".to_string());
+        assert!(!job(Some("2023-05-10 09:04:21.123"), 2060).done_after(&previous));
+        assert!(!job(Some("2023-05-11 10:00:00.000"), 2050).done_after(&previous));
+        assert!(job(Some("2023-05-11 10:00:00.000"), 2070).done_after(&previous));
+        assert!(job(Some("2023-05-11 10:00:00.000"), 2060).done_after(&None));
+        assert!(!job(None, 2060).done_after(&None));
+    }
+
+    #[actix_web::test]
+    async fn get_all_follows_next_links() {
+        let server = MockServer::start();
+        let session = Session { id: "1".to_string(), token: "token".to_string() };
+        server.route(
+            "/api/DeviceService/Devices?$top=1000",
+            serde_json::json!({"value": [1], "@odata.nextLink": "/api/DeviceService/Devices?$skip=1&$top=1000"}),
+        );
+        server.route(
+            "/api/DeviceService/Devices?$skip=1&$top=1000",
+            serde_json::json!({"value": [2], "@odata.nextLink": server.link("/api/DeviceService/Devices?$skip=2&$top=1000")}),
+        );
+        // an empty page ends the walk, even if it links another one
+        server.route(
+            "/api/DeviceService/Devices?$skip=2&$top=1000",
+            serde_json::json!({"value": [], "@odata.nextLink": "/api/DeviceService/Devices?$skip=3&$top=1000"}),
+        );
+
+        let members = session.get_all::<u64>(server.url.join("/api/DeviceService/Devices").unwrap()).await.unwrap();
+        assert_eq!(members, vec![1, 2]);
+        assert_eq!(server.hits("/api/DeviceService/Devices?$skip=3&$top=1000"), 0);
+    }
+
+    #[test]
+    fn first_baseline_targeting_a_node_wins() {
+        let reports = vec![
+            ("a".to_string(), vec![report("node001", "OK", serde_json::json!([]))]),
+            (
+                "b".to_string(),
+                vec![report("node001", "UPGRADE", serde_json::json!([])), report("node002", "UPGRADE", serde_json::json!([]))],
+            ),
+        ];
+        let mut checked = nodes(&["node001", "node002", "node003"]);
+        set_compliance(&mut checked, &reports, None);
+        let states = checked.iter().map(|n| (n.baseline.as_str(), n.compliance.clone())).collect::<Vec<_>>();
+        assert_eq!(
+            states,
+            vec![
+                ("a", ComplianceState::Compliant),
+                ("b", ComplianceState::NonCompliant),
+                ("na", ComplianceState::NoPolicy),
+            ]
+        );
+
+        // an unchecked baseline may target the node without a report
+        let mut checked = nodes(&["node001", "node003"]);
+        set_compliance(&mut checked, &reports, Some("job_timeout"));
+        assert_eq!(checked[0].compliance, ComplianceState::Compliant);
+        assert_eq!(checked[1].compliance, ComplianceState::not_evaluated("job_timeout"));
+    }
+
+    #[test]
+    fn components_of_the_node_report() {
+        let components = serde_json::json!([
+            {"Name": "BIOS", "CurrentVersion": "1.2.0", "Version": "1.3.0", "ComplianceStatus": "UPGRADE"},
+            {"Name": "iDRAC", "CurrentVersion": "7.0", "Version": "7.0", "ComplianceStatus": "OK"},
+            {"Name": "NIC", "ComplianceStatus": "OK"},
+            {"Name": "PSU", "CurrentVersion": "1.0", "ComplianceStatus": "UNKNOWN"},
+        ]);
+        let reports = vec![("a".to_string(), vec![report("node001", "UPGRADE", components)])];
+        let mut checked = nodes(&["node001"]);
+        set_compliance(&mut checked, &reports, None);
+        let component = |name: &str, current: &str, baseline: &str, compliant| Component {
+            name: name.to_string(),
+            current_version: current.to_string(),
+            baseline_version: baseline.to_string(),
+            compliant,
+        };
+        // components of unknown compliance are left out
+        assert_eq!(
+            checked[0].components,
+            vec![
+                component("BIOS", "1.2.0", "1.3.0", false),
+                component("iDRAC", "7.0", "7.0", true),
+                component("NIC", "na", "na", true),
+            ]
+        );
+    }
+}
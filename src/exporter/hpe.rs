@@ -1,43 +1,66 @@
-use log::{error, info};
+use async_trait::async_trait;
+use log::info;
 use reqwest;
-use tokio::time::{Duration, interval};
+use std::sync::Arc;
+use tokio::time::Duration;
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc;
 
+use super::metrics::ConsoleMetrics;
+use super::traits::{CollectError, Collector, Stage};
 use super::Console;
-use super::Node;
-use super::Netbox;
+use super::{ComplianceState, Health, Node};
 
-use crate::exporter::utils::{get_request_builder, deserialize_name};
+use crate::exporter::utils::get_request_builder;
+use crate::settings::{Settings, HPE_CONSOLE};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Device {
     pub uuid: String,
-    #[serde(deserialize_with = "deserialize_name")]
     #[serde(alias = "name")]
     pub device_name: String,
     pub model: String,
+    #[serde(rename = "serialNumber", default)]
+    pub serial_number: Option<String>,
     pub status: String,
+    /// management state, e.g. Monitored, ProfileApplied, Unmanaged
+    #[serde(default)]
+    pub state: Option<String>,
     #[serde(alias = "powerState")]
     pub power_state: String,
     #[serde(skip_deserializing)]
-    pub compliant: String,
+    pub compliance: ComplianceState,
 }
 
 impl From<Device> for Node {
     fn from(d: Device) -> Self {
-        let status = if d.status == "OK" { 1 } else { 0 };
+        let health = match d.status.as_str() {
+            "OK" => Health::Ok,
+            "Warning" => Health::Warning,
+            "Critical" => Health::Critical,
+            _ => Health::Unknown, // Unknown, Disabled
+        };
         let power = if d.power_state == "On" { 1 } else { 0 };
-        let compliant = if d.compliant == "Compliant" { 1 } else { 0 };
+        // oneview only talks to the ilo of servers it monitors or manages
+        let connection = d.state.as_deref().map(|state| match state {
+            "Unknown" | "Unmanaged" | "Unsupported" | "Removing" | "RemoveFailed" | "Removed" => 0,
+            _ => 1,
+        });
         Self {
             device_name: d.device_name,
-            health_status: status,
+            health,
             model: d.model,
             power_state: power,
-            connection_state: 0,
-            compliant: compliant,
+            connection_state: connection,
+            compliance: d.compliance,
+            baseline: "na".to_string(),
+            components: vec![],
             console: "na".to_string(),
+            console_host: "na".to_string(),
             uuid: d.uuid,
+            serial: d.serial_number.unwrap_or_else(|| "na".to_string()),
+            // server hardware in oneview has no asset tag
+            asset_tag: "na".to_string(),
+            netbox_labels: vec![],
         }
     }
 }
@@ -76,151 +99,158 @@ struct ComplianceResult {
     members: Vec<Compliance>,
 }
 
-pub async fn collect_hpe_metrics(settings: Console,  netbox: Netbox, interval_sec: u64, tx: mpsc::Sender<Node>) {
-    info!("hpe client ready. interval: {}", interval_sec);
-    let mut interval = interval(Duration::from_secs(interval_sec * 60));
-    let mut host = settings.host.clone();
-    host.set_path("rest/server-hardware");
+#[derive(Debug)]
+pub struct HpeCollector {
+    settings: Console,
+    interval: Duration,
+}
 
-    loop {
-        interval.tick().await;
-        info!("executing hpe metric collect");
+impl HpeCollector {
+    pub fn new(settings: Console, interval: Duration) -> HpeCollector {
+        info!("hpe client {} ready. interval: {:?}", settings.name, interval);
+        HpeCollector { settings, interval }
+    }
+}
 
-        let token = match get_token(&settings).await {
-            Ok(session) => session.id,
-            Err(e) => {
-                error!("could not get hpe api token: {}", e);
-                continue;
-            }
-        };
+/// Returns a collector per configured oneview console.
+pub fn collectors(settings: &Settings, interval: Duration, _metrics: &ConsoleMetrics) -> Vec<Arc<dyn Collector>> {
+    settings.hpe.iter()
+        .flat_map(|v| v.consoles(HPE_CONSOLE))
+        .map(|c| Arc::new(HpeCollector::new(c, interval)) as Arc<dyn Collector>)
+        .collect()
+}
 
-        let resp = match get_request_builder(
-            reqwest::Method::GET,
-            Some(token.to_string()),
-            None,
-            host.to_owned(),
-        )
-        .send()
-        .await
-        {
-            Ok(v) => v,
-            Err(e) => {
-                error!("error calling lxca: {}", e);
-                continue;
-            }
-        };
+#[async_trait]
+impl Collector for HpeCollector {
+    fn console(&self) -> &Console {
+        &self.settings
+    }
 
-        if resp.status() != reqwest::StatusCode::OK {
-            error!("error api lxca code: {}", resp.status());
-            continue
-        }
+    fn vendor(&self) -> &'static str {
+        "hpe"
+    }
 
-        let json = match resp.json::<APIResponse>().await {
-            Ok(parsed) => parsed,
-            Err(e) => {
-                error!("error parsing lxca json: {}", e);
-                continue;
-            }
-        };
+    fn interval(&self) -> Duration {
+        self.interval
+    }
 
-        for mut device in json.value.clone() {
-            set_device_compliance_status(&settings, token.to_string(), &mut device)
-                .await
-                .unwrap_or_else(|error| {
-                    error!("error checking device compliancy: {:?}", error);
-                });
-        }
+    async fn collect(&self, errors: &mut Vec<CollectError>) -> Result<Vec<Node>, CollectError> {
+        info!("executing hpe metric collect");
+        let settings = &self.settings;
 
-        let mut nodes =  json.value.clone().into_iter().map(|d| Node::from(d)).collect::<Vec<Node>>();
-        let netbox_devices = netbox.get_devices_by_manufacturer(settings.manufacturer_name.to_string()).await 
-        .unwrap_or_else(|e| {
-            error!("error getting netbox devices: {}", e);
-            vec![]
-        });
+        let token = get_token(settings).await
+            .map_err(|e| CollectError::new(Stage::Login, e))?
+            .id;
 
-        for device in netbox_devices {
-            let node = nodes.iter_mut()
-                .find(|n| device.name.to_lowercase().contains(n.device_name.to_lowercase().as_str()));
-            if node.is_some() {
-                let n = node.unwrap();
-                n.console = "oneview".to_string();
-                tx.send(n.clone()).await.unwrap();
-            } else {
-                let n = Node { device_name: device.name, ..Default::default() };
-                tx.send(n.clone()).await.unwrap();
+        let devices = match get_devices(settings, token.to_string()).await {
+            Ok(mut devices) => {
+                for device in devices.iter_mut() {
+                    set_device_compliance_status(settings, token.to_string(), device)
+                        .await
+                        .unwrap_or_else(|e| {
+                            device.compliance = ComplianceState::not_evaluated("request_failed");
+                            errors.push(CollectError::new(Stage::Compliance, format!("checking {}: {}", device.uuid, e)));
+                        });
+                }
+                Ok(devices)
             }
-        }
+            Err(e) => Err(CollectError::new(Stage::Devices, e)),
+        };
 
-        delete_token(&settings, token)
+        delete_token(settings, token)
             .await
-            .unwrap_or_else(|error| {
-                error!("error deleting hpe token: {:?}", error);
-            })
+            .unwrap_or_else(|e| {
+                errors.push(CollectError::new(Stage::Login, format!("deleting token: {}", e)));
+            });
+
+        Ok(devices?.into_iter().map(Node::from).collect::<Vec<Node>>())
     }
+}
 
-    async fn set_device_compliance_status(
-        settings: &Console,
-        token: String,
-        device: &mut Device,
-    ) -> Result<(), reqwest::Error> {
-        let mut host = settings.host.clone();
-        host.set_path("rest/server-profiles");
-        host.set_query(Some(format!("filter='uuid' = '{}'", device.uuid).as_str()));
-        let json = get_request_builder(
-            reqwest::Method::GET, 
-            Some(token.to_string()),
-            None,
-            host
-        )
-            .send()
-            .await?
-            .error_for_status()?
-            .json::<ComplianceResult>()
-            .await?;
-
-        if json.members.len() > 0 {
-            device.compliant = json.members[0].compliance.to_string()
-        }
+async fn get_devices(settings: &Console, token: String) -> Result<Vec<Device>, reqwest::Error> {
+    let mut host = settings.host.clone();
+    host.set_path("rest/server-hardware");
+    let json = get_request_builder(
+        reqwest::Method::GET,
+        Some(token),
+        None,
+        host,
+    )
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<APIResponse>()
+        .await?;
 
-        Ok(())
-    }
+    Ok(json.value)
+}
 
-    async fn get_token(settings: &Console) -> reqwest::Result<Session> {
-        let mut host = settings.host.clone();
-        host.set_path("rest/login-sessions");
-        let username = &Some(settings.username.to_string());
-        let mut auth = std::collections::HashMap::new();
-        auth.insert("authLoginDomain", &settings.domain);
-        auth.insert("userName", username);
-        auth.insert("password", &settings.password);
-
-        let sess = get_request_builder(
-            reqwest::Method::POST, 
-            None,
-            None,
-            host
-        )
-            .json(&auth)
-            .send()
-            .await?
-            .error_for_status()?
-            .json::<Session>()
-            .await?;
-        Ok(sess)
-    }
+async fn set_device_compliance_status(
+    settings: &Console,
+    token: String,
+    device: &mut Device,
+) -> Result<(), reqwest::Error> {
+    let mut host = settings.host.clone();
+    host.set_path("rest/server-profiles");
+    host.set_query(Some(format!("filter='uuid' = '{}'", device.uuid).as_str()));
+    let json = get_request_builder(
+        reqwest::Method::GET, 
+        Some(token.to_string()),
+        None,
+        host
+    )
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<ComplianceResult>()
+        .await?;
 
-    async fn delete_token(settings: &Console, token: String) -> Result<(), reqwest::Error> {
-        let mut host = settings.host.clone();
-        host.set_path("rest/login-sessions");
-        get_request_builder(
-            reqwest::Method::POST,
-            Some(token), 
-            None,
-            host
-        )
-            .send()
-            .await?;
-
-        Ok(())
-    }
+    // servers without a profile have no template to comply with
+    device.compliance = match json.members.first().map(|m| m.compliance.as_str()) {
+        Some("Compliant") => ComplianceState::Compliant,
+        Some("NonCompliant") => ComplianceState::NonCompliant,
+        Some(_) => ComplianceState::not_evaluated("unknown_status"),
+        None => ComplianceState::NoPolicy,
+    };
+
+    Ok(())
+}
+
+async fn get_token(settings: &Console) -> reqwest::Result<Session> {
+    let mut host = settings.host.clone();
+    host.set_path("rest/login-sessions");
+    let username = &Some(settings.username.to_string());
+    let mut auth = std::collections::HashMap::new();
+    auth.insert("authLoginDomain", &settings.domain);
+    auth.insert("userName", username);
+    auth.insert("password", &settings.password);
+
+    let sess = get_request_builder(
+        reqwest::Method::POST, 
+        None,
+        None,
+        host
+    )
+        .json(&auth)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<Session>()
+        .await?;
+    Ok(sess)
+}
+
+async fn delete_token(settings: &Console, token: String) -> Result<(), reqwest::Error> {
+    let mut host = settings.host.clone();
+    host.set_path("rest/login-sessions");
+    get_request_builder(
+        reqwest::Method::POST,
+        Some(token), 
+        None,
+        host
+    )
+        .send()
+        .await?;
+
+    Ok(())
 }
@@ -1,50 +1,74 @@
-use log::{error, info};
+use async_trait::async_trait;
+use log::info;
 use reqwest::{self};
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc;
-use tokio::time::{Duration, interval};
+use std::sync::Arc;
+use tokio::time::Duration;
 use url::Url;
 
+use super::metrics::ConsoleMetrics;
+use super::traits::{CollectError, Collector, Stage};
 use super::Console;
-use super::Node;
-use super::Netbox;
+use super::{ComplianceState, Health, Node};
 
-use crate::exporter::utils::{get_request_builder, deserialize_name};
+use crate::exporter::utils::get_request_builder;
+use crate::settings::{Settings, LENOVO_CONSOLE};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Device {
     pub uuid: String,
     #[serde(alias = "hostname")]
-    #[serde(deserialize_with = "deserialize_name")]
     pub device_name: String,
     pub model: String,
+    #[serde(rename = "serialNumber", default)]
+    pub serial_number: Option<String>,
+    #[serde(rename = "assetTag", default)]
+    pub asset_tag: Option<String>,
     #[serde(alias = "Status")]
     pub status: Status,
+    #[serde(rename = "overallHealthState", default = "default_health_state")]
+    pub health_state: String,
     #[serde(alias = "powerStatus", default = "default_power_state")]
     pub power_state: u8,
     #[serde(skip_deserializing)]
-    pub compliant: String,
+    pub compliance: ComplianceState,
 }
 
 fn default_power_state() -> u8 {
     0
 }
 
+fn default_health_state() -> String {
+    "Unknown".to_string()
+}
+
 impl From<Device> for Node {
     fn from(d: Device) -> Self {
-        let status = if d.status.name == "MANAGED" { 1 } else { 0 };
+        // nodes which are not managed (e.g. offline) have no reliable health state
+        let health = match d.health_state.as_str() {
+            _ if d.status.name != "MANAGED" => Health::Unknown,
+            "Normal" => Health::Ok,
+            "Non-Critical" | "Warning" | "Minor-Failure" => Health::Warning,
+            "Critical" | "Major-Failure" | "Non-Recoverable" => Health::Critical,
+            _ => Health::Unknown,
+        };
         let power = if d.power_state == 5 { 1 } else { 0 };
-        let compliant = if d.compliant == "yes" { 1 } else { 0 };
-        let connection = 0;
+        let connection = if d.status.name == "MANAGED" { 1 } else { 0 };
         Self {
             device_name: d.device_name,
-            health_status: status,
+            health,
             model: d.model,
             power_state: power,
-            connection_state: connection,
-            compliant: compliant,
+            connection_state: Some(connection),
+            compliance: d.compliance,
+            baseline: "na".to_string(),
+            components: vec![],
             console: "na".to_string(),
+            console_host: "na".to_string(),
             uuid: d.uuid,
+            serial: d.serial_number.unwrap_or_else(|| "na".to_string()),
+            asset_tag: d.asset_tag.filter(|t| !t.is_empty()).unwrap_or_else(|| "na".to_string()),
+            netbox_labels: vec![],
         }
     }
 }
@@ -100,48 +124,64 @@ fn default_resource() -> String {
     "NA".to_string()
 }
 
-pub async fn collect_lenovo_metrics(settings: Console, netbox: Netbox, interval_sec: u64, tx: mpsc::Sender<Node>) {
-    info!("lenovo client ready. interval: {}", interval_sec);
-    let mut interval = interval(Duration::from_secs(interval_sec * 60));
-    let mut host = settings.host.clone();
-    host.set_path("nodes");
+#[derive(Debug)]
+pub struct LenovoCollector {
+    settings: Console,
+    interval: Duration,
+}
 
-    loop {
-        interval.tick().await;
-        info!("executing lenovo metric collect");
+impl LenovoCollector {
+    pub fn new(settings: Console, interval: Duration) -> LenovoCollector {
+        info!("lenovo client {} ready. interval: {:?}", settings.name, interval);
+        LenovoCollector { settings, interval }
+    }
+}
 
-        let devices = get_nodes(settings.clone(), host.clone()).await.unwrap_or_else(|e| {
-            error!("error getting nodes: {}", e);
-            vec![]
-        });
-        let mut cloned_devices = devices.clone();
-        for device in devices{
-            attach_device_compliance_policy(settings.clone(), device.uuid.to_string()).await
-                .unwrap_or_else(|e| {
-                    error!("error getting nodes: {}", e);
-                });
+/// Returns a collector per configured lxca console.
+pub fn collectors(settings: &Settings, interval: Duration, _metrics: &ConsoleMetrics) -> Vec<Arc<dyn Collector>> {
+    settings.lenovo.iter()
+        .flat_map(|v| v.consoles(LENOVO_CONSOLE))
+        .map(|c| Arc::new(LenovoCollector::new(c, interval)) as Arc<dyn Collector>)
+        .collect()
+}
 
-        }
-        set_device_compliance_status(&settings, &mut cloned_devices).await;
-        let mut nodes =  cloned_devices.clone().into_iter().map(|d| Node::from(d)).collect::<Vec<Node>>();
-        let netbox_devices = netbox.get_devices_by_manufacturer(settings.manufacturer_name.to_string()).await 
-        .unwrap_or_else(|e| {
-            error!("error getting netbox devices: {}", e);
-            vec![]
-        });
+#[async_trait]
+impl Collector for LenovoCollector {
+    fn console(&self) -> &Console {
+        &self.settings
+    }
 
-        for device in netbox_devices {
-            let node = nodes.iter_mut()
-                .find(|n| device.name.to_lowercase().contains(n.device_name.to_lowercase().as_str()));
-            if node.is_some() {
-                let n = node.unwrap();
-                n.console = "lxca".to_string();
-                tx.send(n.clone()).await.unwrap();
-            } else {
-                let n = Node { device_name: device.name, ..Default::default() };
-                tx.send(n.clone()).await.unwrap();
-            }
+    fn vendor(&self) -> &'static str {
+        "lenovo"
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    async fn collect(&self, errors: &mut Vec<CollectError>) -> Result<Vec<Node>, CollectError> {
+        info!("executing lenovo metric collect");
+        let mut host = self.settings.host.clone();
+        host.set_path("nodes");
+
+        let mut devices = get_nodes(self.settings.clone(), host).await
+            .map_err(|e| CollectError::new(Stage::Devices, e))?;
+        for device in devices.iter_mut() {
+            // devices without a persisted result are not targeted by the policy
+            device.compliance = match attach_device_compliance_policy(self.settings.clone(), device.uuid.to_string()).await {
+                Ok(_) => ComplianceState::NoPolicy,
+                Err(e) => {
+                    errors.push(CollectError::new(Stage::Compliance, format!("attaching policy to {}: {}", device.uuid, e)));
+                    ComplianceState::not_evaluated("policy_assignment_failed")
+                }
+            };
         }
+        set_device_compliance_status(&self.settings, &mut devices).await
+            .unwrap_or_else(|e| {
+                errors.push(CollectError::new(Stage::Compliance, e));
+                devices.iter_mut().for_each(|d| d.compliance = ComplianceState::not_evaluated("request_failed"));
+            });
+        Ok(devices.into_iter().map(Node::from).collect::<Vec<Node>>())
     }
 }
 
@@ -177,7 +217,7 @@ async fn attach_device_compliance_policy(
         }],
     };
 
-    let _ = get_request_builder(
+    get_request_builder(
         reqwest::Method::POST,
         None,
         Some(&settings), 
@@ -185,49 +225,41 @@ async fn attach_device_compliance_policy(
     )
         .json(&body)
         .send()
-        .await?;
+        .await?
+        .error_for_status()?;
 
     Ok(())
 }
 
-async fn set_device_compliance_status(settings: &Console, devices: &mut Vec<Device>) {
+async fn set_device_compliance_status(settings: &Console, devices: &mut [Device]) -> Result<(), reqwest::Error> {
     let mut host = settings.host.clone();
     host.set_path("/compliancePolicies/persistedResult");
     host.set_query(Some("type=SERVER"));
-    let resp = match get_request_builder(
+    let results = get_request_builder(
         reqwest::Method::GET, 
         None,
-        Some(&settings), 
+        Some(settings), 
         host,
     )
         .send()
-        .await
-    {
-        Ok(r) => r,
-        Err(e) => {
-            error!("error calling lxca: {}", e);
-            return;
-        }
-    };
+        .await?
+        .error_for_status()?
+        .json::<ComplianceResults>()
+        .await?;
 
-    if resp.status() == reqwest::StatusCode::OK {
-        let results = match resp.json::<ComplianceResults>().await {
-            Ok(parsed) => parsed,
-            Err(e) => {
-                error!("error parsing lxca json: {}", e);
-                return;
+    let cloned: Option<Vec<ComplianceServer>> = results.all.first().and_then(|a| a.racklist.clone());
+    cloned.unwrap_or(
+        Vec::<ComplianceServer>::new()
+    ).iter()
+        .filter(|c| c.policy_name == settings.policy_name)
+        .for_each(|c| {
+            if let Some(d) = devices.iter_mut().find(|d| d.uuid == c.uuid) {
+                d.compliance = match c.endpoint_compliant.as_str() {
+                    "yes" => ComplianceState::Compliant,
+                    "no" => ComplianceState::NonCompliant,
+                    _ => ComplianceState::not_evaluated("unknown_status"),
+                };
             }
-        };
-
-        let cloned: Option<Vec<ComplianceServer>> = results.all[0].racklist.clone();
-        cloned.unwrap_or(
-            Vec::<ComplianceServer>::new()
-        ).iter()
-            .filter(|c| c.policy_name == settings.policy_name)
-            .for_each(|c| {
-                devices.iter_mut()  
-                    .find(|d| d.uuid == c.uuid)
-                    .map(|d| d.compliant = c.endpoint_compliant.to_string());
-            });
-    }
+        });
+    Ok(())
 }
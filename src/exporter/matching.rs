@@ -0,0 +1,333 @@
+use log::warn;
+
+use crate::netbox::NetboxDevice;
+use crate::settings::{Console, MatchStrategy, NameRule};
+
+use super::{AmbiguousMatch, MisassignedDevice, Node, OrphanedDevice, Snapshot, UnmanagedDevice};
+
+/// Outcome of looking up the console node of a netbox device.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Match {
+    Found(usize),
+    /// the first strategy with a hit found several nodes, none of them is picked
+    Ambiguous(Vec<usize>),
+    NotFound,
+}
+
+#[derive(Debug, Clone)]
+pub struct Matcher {
+    console: String,
+    console_host: String,
+    strategies: Vec<MatchStrategy>,
+    uuid_field: String,
+    rules: Vec<NameRule>,
+    /// names of all configured consoles, console fields naming others are ignored
+    consoles: Vec<String>,
+}
+
+impl Matcher {
+    pub fn new(console: &Console, consoles: &[String]) -> Matcher {
+        Matcher {
+            console: console.name.to_string(),
+            console_host: console.host.to_string(),
+            strategies: console.match_strategies.clone(),
+            uuid_field: console.uuid_field.clone(),
+            rules: console.name_rules.clone(),
+            consoles: consoles.to_vec(),
+        }
+    }
+
+    /// Matches the console's nodes with the netbox devices it is expected to manage.
+    /// Nodes claimed by several devices and devices matching several nodes are
+    /// reported as ambiguous instead of picking one of them. Devices assigned to
+    /// another console are only reported if found, as misassigned. Devices assigned to
+    /// an unknown console are handled like unassigned ones, but found ones are misassigned.
+    pub fn reconcile(&self, nodes: Vec<Node>, devices: Vec<NetboxDevice>) -> Snapshot {
+        let mut snapshot = Snapshot { console: self.console.to_string(), ..Default::default() };
+        let found = devices.iter().map(|d| self.find(d, &nodes)).collect::<Vec<Match>>();
+        // number of devices claiming a node, all claims of a shared node are ambiguous
+        let mut claims = vec![0; nodes.len()];
+        let mut matched = vec![false; nodes.len()];
+        for m in found.iter() {
+            match m {
+                Match::Found(i) => {
+                    claims[*i] += 1;
+                    matched[*i] = true;
+                }
+                Match::Ambiguous(found) => found.iter().for_each(|i| matched[*i] = true),
+                Match::NotFound => {}
+            }
+        }
+        for (device, m) in devices.into_iter().zip(found) {
+            let expected = device.expected_console.clone().filter(|c| *c != self.console);
+            if let Some(unknown) = expected.as_ref().filter(|c| !self.consoles.contains(c)) {
+                warn!("{}: netbox device {} is assigned to unknown console {}", self.console, device.name, unknown);
+            }
+            let elsewhere = expected.as_ref().is_some_and(|c| self.consoles.contains(c));
+            match m {
+                Match::Found(i) if claims[i] == 1 => {
+                    if let Some(expected) = expected {
+                        warn!("{}: netbox device {} is assigned to console {}", self.console, device.name, expected);
+                        snapshot.misassigned.push(MisassignedDevice {
+                            console: self.console.to_string(),
+                            expected_console: expected,
+                            device_name: device.name.to_string(),
+                            netbox_id: device.id,
+                        });
+                    }
+                    let n = &nodes[i];
+                    snapshot.nodes.push(Node {
+                        device_name: self.normalize(&n.device_name),
+                        console: self.console.to_string(),
+                        console_host: self.console_host.to_string(),
+                        netbox_labels: device.labels.clone(),
+                        ..n.clone()
+                    });
+                }
+                Match::Found(i) => snapshot.ambiguous.push(self.ambiguous(&device, &nodes, &[i])),
+                Match::Ambiguous(found) => snapshot.ambiguous.push(self.ambiguous(&device, &nodes, &found)),
+                // the console it is assigned to reports it if it is missing there
+                Match::NotFound if elsewhere => {}
+                Match::NotFound => snapshot.unmanaged.push(UnmanagedDevice {
+                    site: device.site_name(),
+                    device_name: device.name,
+                    netbox_id: device.id,
+                    console: self.console.to_string(),
+                }),
+            }
+        }
+        snapshot.orphaned = nodes
+            .into_iter()
+            .zip(matched)
+            .filter(|(_, matched)| !matched)
+            .map(|(n, _)| OrphanedDevice {
+                console: self.console.to_string(),
+                device_name: self.normalize(&n.device_name),
+                model: n.model,
+                uuid: n.uuid,
+                serial: n.serial,
+            })
+            .collect();
+        snapshot
+    }
+
+    fn ambiguous(&self, device: &NetboxDevice, nodes: &[Node], found: &[usize]) -> AmbiguousMatch {
+        let candidates = found
+            .iter()
+            .map(|i| self.normalize(&nodes[*i].device_name))
+            .collect::<Vec<String>>()
+            .join(",");
+        warn!("{}: netbox device {} matches ambiguous nodes {}", self.console, device.name, candidates);
+        AmbiguousMatch {
+            console: self.console.to_string(),
+            device_name: device.name.to_string(),
+            netbox_id: device.id,
+            candidates,
+        }
+    }
+
+    /// Tries the strategies in their configured order and returns the node indexes
+    /// found by the first strategy which finds any.
+    pub fn find(&self, device: &NetboxDevice, nodes: &[Node]) -> Match {
+        for strategy in self.strategies.iter() {
+            let found = nodes
+                .iter()
+                .enumerate()
+                .filter(|(_, n)| self.matches(*strategy, device, n))
+                .map(|(i, _)| i)
+                .collect::<Vec<usize>>();
+            match found.len() {
+                0 => continue,
+                1 => return Match::Found(found[0]),
+                _ => return Match::Ambiguous(found),
+            }
+        }
+        Match::NotFound
+    }
+
+    fn matches(&self, strategy: MatchStrategy, device: &NetboxDevice, node: &Node) -> bool {
+        match strategy {
+            MatchStrategy::Hostname => same_key(&device.name.to_lowercase(), &node.device_name.to_lowercase()),
+            MatchStrategy::NormalizedHostname => {
+                same_key(&self.normalize(&device.name), &self.normalize(&node.device_name))
+            }
+            MatchStrategy::Serial => same_key(&device.serial.to_lowercase(), &node.serial.to_lowercase()),
+            MatchStrategy::AssetTag => {
+                same_key(&device.asset_tag.clone().unwrap_or_default(), &node.asset_tag)
+            }
+            MatchStrategy::Uuid => same_key(
+                &device.custom_field(&self.uuid_field).unwrap_or_default().to_lowercase(),
+                &node.uuid.to_lowercase(),
+            ),
+        }
+    }
+
+    /// Applies the name rules in their configured order.
+    pub fn normalize(&self, name: &str) -> String {
+        let mut name = name.to_string();
+        for rule in self.rules.iter() {
+            name = match rule {
+                NameRule::StripDomain => name.split('.').next().unwrap_or_default().to_string(),
+                NameRule::Lowercase => name.to_lowercase(),
+                NameRule::Regex { pattern, replace } => pattern.replace_all(&name, replace.as_str()).to_string(),
+                NameRule::Prefix { from, to } => match name.strip_prefix(from.as_str()) {
+                    Some(stripped) => format!("{}{}", to, stripped),
+                    None => name,
+                },
+                NameRule::Suffix { from, to } => match name.strip_suffix(from.as_str()) {
+                    Some(stripped) => format!("{}{}", stripped, to),
+                    None => name,
+                },
+            };
+        }
+        name
+    }
+}
+
+/// Keys only match if they are known on both sides.
+fn same_key(a: &str, b: &str) -> bool {
+    !a.is_empty() && a != "na" && a == b
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Vendor;
+
+    fn matcher(settings: serde_json::Value) -> Matcher {
+        let mut vendor = serde_json::json!({
+            "host": "https://ome.example.com",
+            "username": "admin",
+            "manufacturer_name": "dell",
+        });
+        vendor.as_object_mut().unwrap().extend(settings.as_object().unwrap().clone());
+        let vendor: Vendor = serde_json::from_value(vendor).unwrap();
+        Matcher::new(&vendor.consoles("openmanage")[0], &["openmanage".to_string(), "ome-b".to_string()])
+    }
+
+    fn device(id: u64, name: &str, serial: &str) -> NetboxDevice {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "name": name,
+            "serial": serial,
+        }))
+        .unwrap()
+    }
+
+    fn node(name: &str, serial: &str) -> Node {
+        Node {
+            device_name: name.to_string(),
+            serial: serial.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn same_key_requires_known_values() {
+        assert!(same_key("abc", "abc"));
+        assert!(!same_key("abc", "abd"));
+        assert!(!same_key("", ""));
+        assert!(!same_key("na", "na"));
+    }
+
+    #[test]
+    fn default_rules_strip_domain_and_lowercase() {
+        assert_eq!(matcher(serde_json::json!({})).normalize("Node001-BB1.cc.example.com"), "node001-bb1");
+    }
+
+    #[test]
+    fn rules_apply_in_order() {
+        let m = matcher(serde_json::json!({
+            "name_rules": [
+                "lowercase",
+                {"prefix": {"from": "r1-", "to": ""}},
+                {"suffix": {"from": "-mgmt", "to": ""}},
+                {"regex": {"pattern": "^node(\\d+)$", "replace": "n$1"}},
+            ],
+        }));
+        assert_eq!(m.normalize("R1-Node001-MGMT"), "n001");
+        assert_eq!(m.normalize("other"), "other");
+    }
+
+    #[test]
+    fn chart_rules_only_strip_the_rack_marker() {
+        let m = matcher(serde_json::json!({
+            "name_rules": ["strip_domain", "lowercase", {"regex": {"pattern": "^(node\\d+)r", "replace": "$1"}}],
+        }));
+        assert_eq!(m.normalize("node001r-bb091.cc.example.com"), "node001-bb091");
+        assert_eq!(m.normalize("node001-bb091"), "node001-bb091");
+        assert_eq!(m.normalize("server-rack01"), "server-rack01");
+    }
+
+    #[test]
+    fn first_strategy_with_a_hit_wins() {
+        let m = matcher(serde_json::json!({"match_strategies": ["serial", "hostname"]}));
+        let nodes = vec![node("node001", "AAA"), node("node002", "BBB")];
+        assert_eq!(m.find(&device(1, "node001", "BBB"), &nodes), Match::Found(1));
+        assert_eq!(m.find(&device(1, "NODE001", ""), &nodes), Match::Found(0));
+        assert_eq!(m.find(&device(1, "node003", "CCC"), &nodes), Match::NotFound);
+    }
+
+    #[test]
+    fn several_nodes_of_one_strategy_are_ambiguous() {
+        let m = matcher(serde_json::json!({"match_strategies": ["serial"]}));
+        let nodes = vec![node("node001", "AAA"), node("node002", "AAA")];
+        assert_eq!(m.find(&device(1, "node001", "AAA"), &nodes), Match::Ambiguous(vec![0, 1]));
+    }
+
+    #[test]
+    fn reconcile_reports_unmanaged_and_orphaned() {
+        let m = matcher(serde_json::json!({}));
+        let nodes = vec![node("node001.example.com", "AAA"), node("node002", "BBB")];
+        let devices = vec![device(1, "node001", "AAA"), device(2, "node003", "CCC")];
+        let snapshot = m.reconcile(nodes, devices);
+        assert_eq!(snapshot.nodes.len(), 1);
+        assert_eq!(snapshot.nodes[0].device_name, "node001");
+        assert_eq!(snapshot.nodes[0].console, "openmanage");
+        assert_eq!(snapshot.unmanaged.len(), 1);
+        assert_eq!(snapshot.unmanaged[0].netbox_id, 2);
+        assert_eq!(snapshot.orphaned.len(), 1);
+        assert_eq!(snapshot.orphaned[0].device_name, "node002");
+        assert_eq!(snapshot.orphaned[0].serial, "BBB");
+    }
+
+    #[test]
+    fn reconcile_exports_none_of_several_claims() {
+        let m = matcher(serde_json::json!({"match_strategies": ["serial"]}));
+        let nodes = vec![node("node001", "AAA")];
+        let devices = vec![device(1, "node001", "AAA"), device(2, "node001-old", "AAA")];
+        let snapshot = m.reconcile(nodes, devices);
+        assert!(snapshot.nodes.is_empty());
+        assert!(snapshot.orphaned.is_empty());
+        let ids = snapshot.ambiguous.iter().map(|a| a.netbox_id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![1, 2]);
+    }
+
+    #[test]
+    fn reconcile_skips_devices_of_other_consoles() {
+        let m = matcher(serde_json::json!({}));
+        let mut missing = device(1, "node001", "AAA");
+        missing.expected_console = Some("ome-b".to_string());
+        let mut found = device(2, "node002", "BBB");
+        found.expected_console = Some("ome-b".to_string());
+        let snapshot = m.reconcile(vec![node("node002", "BBB")], vec![missing, found]);
+        assert!(snapshot.unmanaged.is_empty());
+        assert_eq!(snapshot.nodes.len(), 1);
+        assert_eq!(snapshot.misassigned.len(), 1);
+        assert_eq!(snapshot.misassigned[0].expected_console, "ome-b");
+    }
+
+    #[test]
+    fn reconcile_reports_devices_of_unknown_consoles() {
+        let m = matcher(serde_json::json!({}));
+        let mut missing = device(1, "node001", "AAA");
+        missing.expected_console = Some("ome-typo".to_string());
+        let mut found = device(2, "node002", "BBB");
+        found.expected_console = Some("ome-typo".to_string());
+        let snapshot = m.reconcile(vec![node("node002", "BBB")], vec![missing, found]);
+        assert_eq!(snapshot.unmanaged.len(), 1);
+        assert_eq!(snapshot.unmanaged[0].netbox_id, 1);
+        assert_eq!(snapshot.misassigned.len(), 1);
+        assert_eq!(snapshot.misassigned[0].expected_console, "ome-typo");
+    }
+}
@@ -0,0 +1,427 @@
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::registry::Registry;
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::dell::JobState;
+use super::traits::{CollectError, Stage};
+use super::{AmbiguousMatch, ComplianceState, Component, MisassignedDevice, Node, OrphanedDevice, Snapshot, UnmanagedDevice};
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct CollectorLabels {
+    pub console: String,
+    pub console_host: String,
+    pub vendor: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct ConsoleLabels {
+    pub console: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct ErrorLabels {
+    pub console: String,
+    pub stage: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct BaselineLabels {
+    pub console: String,
+    pub baseline: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct JobStateLabels {
+    pub console: String,
+    pub baseline: String,
+    pub state: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct NodeLabels {
+    pub console: String,
+    pub device_name: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct NodeHealthLabels {
+    pub console: String,
+    pub device_name: String,
+    pub state: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct NodeComplianceLabels {
+    pub console: String,
+    pub device_name: String,
+    pub baseline: String,
+    pub state: String,
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct NodeComponentLabels {
+    pub console: String,
+    pub device_name: String,
+    pub baseline: String,
+    pub component: String,
+    pub current_version: String,
+    pub baseline_version: String,
+}
+
+impl NodeComponentLabels {
+    fn new(n: &Node, c: &Component) -> Self {
+        Self {
+            console: n.console.to_string(),
+            device_name: n.device_name.to_string(),
+            baseline: n.baseline.to_string(),
+            component: c.name.to_string(),
+            current_version: c.current_version.to_string(),
+            baseline_version: c.baseline_version.to_string(),
+        }
+    }
+}
+
+/// Labels of `node_info`, the netbox labels are configurable and thus not a fixed set.
+pub type NodeInfoLabels = Vec<(String, String)>;
+
+impl From<&Node> for NodeLabels {
+    fn from(n: &Node) -> Self {
+        Self {
+            console: n.console.to_string(),
+            device_name: n.device_name.to_string(),
+        }
+    }
+}
+
+impl From<&Node> for NodeHealthLabels {
+    fn from(n: &Node) -> Self {
+        Self {
+            console: n.console.to_string(),
+            device_name: n.device_name.to_string(),
+            state: n.health.as_str().to_string(),
+        }
+    }
+}
+
+impl From<&Node> for NodeComplianceLabels {
+    fn from(n: &Node) -> Self {
+        Self {
+            console: n.console.to_string(),
+            device_name: n.device_name.to_string(),
+            baseline: n.baseline.to_string(),
+            state: n.compliance.as_str().to_string(),
+            reason: n.compliance.reason().to_string(),
+        }
+    }
+}
+
+fn node_info_labels(n: &Node) -> NodeInfoLabels {
+    let mut labels = vec![
+        ("console".to_string(), n.console.to_string()),
+        ("console_host".to_string(), n.console_host.to_string()),
+        ("device_name".to_string(), n.device_name.to_string()),
+        ("model".to_string(), n.model.to_string()),
+        ("uuid".to_string(), n.uuid.to_string()),
+        ("serial".to_string(), n.serial.to_string()),
+    ];
+    labels.extend(n.netbox_labels.iter().cloned());
+    labels
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ConsoleMetrics {
+    pub collectors_active: Family<CollectorLabels, Gauge>,
+    pub collect_success: Family<ConsoleLabels, Gauge>,
+    pub collect_duration_seconds: Family<ConsoleLabels, Gauge<f64, AtomicU64>>,
+    pub last_success_timestamp_seconds: Family<ConsoleLabels, Gauge<f64, AtomicU64>>,
+    pub collect_errors: Family<ErrorLabels, Counter>,
+    pub netbox_auth_errors: Counter,
+    pub netbox_devices_reported: Family<ConsoleLabels, Gauge>,
+    pub netbox_devices_collected: Family<ConsoleLabels, Gauge>,
+    pub netbox_inventory_age_seconds: Family<ConsoleLabels, Gauge<f64, AtomicU64>>,
+    pub compliance_job_state: Family<JobStateLabels, Gauge>,
+    pub compliance_job_duration_seconds: Family<BaselineLabels, Gauge<f64, AtomicU64>>,
+    pub compliance_report_last_run_timestamp_seconds: Family<BaselineLabels, Gauge<f64, AtomicU64>>,
+    pub node_info: Family<NodeInfoLabels, Gauge>,
+    pub node_health: Family<NodeHealthLabels, Gauge>,
+    pub node_power_on: Family<NodeLabels, Gauge>,
+    pub node_connected: Family<NodeLabels, Gauge>,
+    pub node_compliant: Family<NodeLabels, Gauge>,
+    pub node_compliance: Family<NodeComplianceLabels, Gauge>,
+    pub node_component_compliant: Family<NodeComponentLabels, Gauge>,
+    pub unmanaged_device: Family<UnmanagedDevice, Gauge>,
+    pub orphaned_device: Family<OrphanedDevice, Gauge>,
+    pub ambiguous_match: Family<AmbiguousMatch, Gauge>,
+    pub misassigned_device: Family<MisassignedDevice, Gauge>,
+    lock: Arc<RwLock<()>>,
+}
+
+impl ConsoleMetrics {
+    pub fn register(&self, registry: &mut Registry) {
+        let registry = registry.sub_registry_with_prefix("hardware_console");
+        registry.register(
+            "collector_active",
+            "console collectors enabled in this exporter",
+            self.collectors_active.clone(),
+        );
+        registry.register(
+            "collect_success",
+            "1 if the last collect run of the console finished without errors",
+            self.collect_success.clone(),
+        );
+        registry.register(
+            "collect_duration_seconds",
+            "duration of the last collect run of the console",
+            self.collect_duration_seconds.clone(),
+        );
+        registry.register(
+            "last_success_timestamp_seconds",
+            "unix timestamp of the last collect run without errors",
+            self.last_success_timestamp_seconds.clone(),
+        );
+        registry.register(
+            "collect_errors",
+            "errors during collect runs by console and stage",
+            self.collect_errors.clone(),
+        );
+        registry.register(
+            "netbox_auth_errors",
+            "netbox requests rejected with 401 or 403, check the netbox token",
+            self.netbox_auth_errors.clone(),
+        );
+        registry.register(
+            "netbox_devices_reported",
+            "number of devices netbox reported for the console's query",
+            self.netbox_devices_reported.clone(),
+        );
+        registry.register(
+            "netbox_devices_collected",
+            "number of devices fetched from netbox for the console's query, lower than reported if truncated",
+            self.netbox_devices_collected.clone(),
+        );
+        registry.register(
+            "netbox_inventory_age_seconds",
+            "age of the netbox inventory the console's nodes were last matched against",
+            self.netbox_inventory_age_seconds.clone(),
+        );
+        registry.register(
+            "compliance_job_state",
+            "state (completed, warning, failed, aborted, timeout) of the last compliance job of a baseline, 1 for the current state",
+            self.compliance_job_state.clone(),
+        );
+        registry.register(
+            "compliance_job_duration_seconds",
+            "time the last compliance job of a baseline was waited for",
+            self.compliance_job_duration_seconds.clone(),
+        );
+        registry.register(
+            "compliance_report_last_run_timestamp_seconds",
+            "unix timestamp of the compliance run the exported reports of a baseline stem from",
+            self.compliance_report_last_run_timestamp_seconds.clone(),
+        );
+        registry.register(
+            "node_info",
+            "static information about a node, value is always 1",
+            self.node_info.clone(),
+        );
+        registry.register(
+            "node_health",
+            "health state (ok, warning, critical, unknown) of the node, value is always 1",
+            self.node_health.clone(),
+        );
+        registry.register(
+            "node_power_on",
+            "1 if the node is powered on",
+            self.node_power_on.clone(),
+        );
+        registry.register(
+            "node_connected",
+            "1 if the console is connected to the node's management controller, missing if the console can't tell",
+            self.node_connected.clone(),
+        );
+        registry.register(
+            "node_compliant",
+            "1 if the node complies with the console's firmware policy, missing if not evaluated",
+            self.node_compliant.clone(),
+        );
+        registry.register(
+            "node_compliance",
+            "compliance state (compliant, non_compliant, not_evaluated, no_policy) of the node against its baseline, value is always 1",
+            self.node_compliance.clone(),
+        );
+        registry.register(
+            "node_component_compliant",
+            "1 if the firmware component of the node complies with the node's baseline",
+            self.node_component_compliant.clone(),
+        );
+        registry.register(
+            "unmanaged_device",
+            "netbox device not found on the console expected to manage it, value is always 1",
+            self.unmanaged_device.clone(),
+        );
+        registry.register(
+            "orphaned_device",
+            "console device without a netbox device, value is always 1",
+            self.orphaned_device.clone(),
+        );
+        registry.register(
+            "ambiguous_match",
+            "netbox device which could not be matched to a single console node, value is always 1",
+            self.ambiguous_match.clone(),
+        );
+        registry.register(
+            "misassigned_device",
+            "netbox device found on another console than its netbox console field names, value is always 1",
+            self.misassigned_device.clone(),
+        );
+    }
+
+    pub fn add_collector(&self, labels: CollectorLabels) {
+        for stage in [Stage::Login, Stage::Devices, Stage::Compliance, Stage::Netbox] {
+            // initialize the counters, so increases right after startup are visible
+            let _ = self.collect_errors.get_or_create(&ErrorLabels {
+                console: labels.console.to_string(),
+                stage: stage.as_str().to_string(),
+            });
+        }
+        self.collectors_active.get_or_create(&labels).set(1);
+    }
+
+    /// Records the outcome of a collect run, a run succeeded if it had no errors.
+    pub fn observe_collect(&self, console: &str, duration: Duration, errors: &[CollectError]) {
+        let labels = ConsoleLabels { console: console.to_string() };
+        for e in errors {
+            self.collect_errors
+                .get_or_create(&ErrorLabels {
+                    console: console.to_string(),
+                    stage: e.stage.as_str().to_string(),
+                })
+                .inc();
+        }
+        self.collect_duration_seconds.get_or_create(&labels).set(duration.as_secs_f64());
+        if !errors.is_empty() {
+            self.collect_success.get_or_create(&labels).set(0);
+            return;
+        }
+        self.collect_success.get_or_create(&labels).set(1);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.last_success_timestamp_seconds.get_or_create(&labels).set(now.as_secs_f64());
+    }
+
+    pub fn observe_netbox(&self, console: &str, reported: usize, collected: usize, age: Duration) {
+        let labels = ConsoleLabels { console: console.to_string() };
+        self.netbox_devices_reported.get_or_create(&labels).set(reported as i64);
+        self.netbox_devices_collected.get_or_create(&labels).set(collected as i64);
+        self.netbox_inventory_age_seconds.get_or_create(&labels).set(age.as_secs_f64());
+    }
+
+    /// Records the outcome of a compliance job, `last_run` is only known for completed jobs.
+    pub fn observe_compliance_job(
+        &self,
+        console: &str,
+        baseline: &str,
+        state: JobState,
+        duration: Duration,
+        last_run: Option<f64>,
+    ) {
+        let labels = BaselineLabels { console: console.to_string(), baseline: baseline.to_string() };
+        for s in JobState::ALL {
+            let value = if s == state { 1 } else { 0 };
+            self.compliance_job_state
+                .get_or_create(&JobStateLabels {
+                    console: console.to_string(),
+                    baseline: baseline.to_string(),
+                    state: s.as_str().to_string(),
+                })
+                .set(value);
+        }
+        self.compliance_job_duration_seconds.get_or_create(&labels).set(duration.as_secs_f64());
+        if let Some(last_run) = last_run {
+            self.compliance_report_last_run_timestamp_seconds.get_or_create(&labels).set(last_run);
+        }
+    }
+
+    /// Blocks node updates while the returned guard is alive, so an encoded
+    /// registry never contains a half replaced snapshot.
+    pub fn read(&self) -> RwLockReadGuard<'_, ()> {
+        self.lock.read().unwrap()
+    }
+
+    /// Replaces the series of the `previous` snapshot of a console by the ones of `snapshot`.
+    /// Series of nodes which disappeared or changed their labels are removed.
+    pub fn replace_snapshot(&self, previous: &Snapshot, snapshot: &Snapshot) {
+        let _guard = self.lock.write().unwrap();
+        previous.nodes.iter().for_each(|n| self.remove_node(n));
+        previous.unmanaged.iter().for_each(|d| {
+            self.unmanaged_device.remove(d);
+        });
+        previous.orphaned.iter().for_each(|d| {
+            self.orphaned_device.remove(d);
+        });
+        previous.ambiguous.iter().for_each(|d| {
+            self.ambiguous_match.remove(d);
+        });
+        previous.misassigned.iter().for_each(|d| {
+            self.misassigned_device.remove(d);
+        });
+        snapshot.nodes.iter().for_each(|n| self.set_node(n));
+        snapshot.unmanaged.iter().for_each(|d| {
+            self.unmanaged_device.get_or_create(d).set(1);
+        });
+        snapshot.orphaned.iter().for_each(|d| {
+            self.orphaned_device.get_or_create(d).set(1);
+        });
+        snapshot.ambiguous.iter().for_each(|d| {
+            self.ambiguous_match.get_or_create(d).set(1);
+        });
+        snapshot.misassigned.iter().for_each(|d| {
+            self.misassigned_device.get_or_create(d).set(1);
+        });
+    }
+
+    fn remove_node(&self, node: &Node) {
+        let labels = NodeLabels::from(node);
+        self.node_info.remove(&node_info_labels(node));
+        self.node_health.remove(&NodeHealthLabels::from(node));
+        self.node_power_on.remove(&labels);
+        if node.connection_state.is_some() {
+            self.node_connected.remove(&labels);
+        }
+        self.node_compliant.remove(&labels);
+        self.node_compliance.remove(&NodeComplianceLabels::from(node));
+        for c in node.components.iter() {
+            self.node_component_compliant.remove(&NodeComponentLabels::new(node, c));
+        }
+    }
+
+    fn set_node(&self, node: &Node) {
+        let labels = NodeLabels::from(node);
+        self.node_info.get_or_create(&node_info_labels(node)).set(1);
+        self.node_health.get_or_create(&NodeHealthLabels::from(node)).set(1);
+        self.node_power_on.get_or_create(&labels).set(i64::from(node.power_state));
+        if let Some(connection) = node.connection_state {
+            self.node_connected.get_or_create(&labels).set(i64::from(connection));
+        }
+        self.node_compliance.get_or_create(&NodeComplianceLabels::from(node)).set(1);
+        for c in node.components.iter() {
+            self.node_component_compliant
+                .get_or_create(&NodeComponentLabels::new(node, c))
+                .set(if c.compliant { 1 } else { 0 });
+        }
+        match node.compliance {
+            ComplianceState::Compliant => {
+                self.node_compliant.get_or_create(&labels).set(1);
+            }
+            ComplianceState::NonCompliant => {
+                self.node_compliant.get_or_create(&labels).set(0);
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use async_trait::async_trait;
+use std::fmt;
+use std::sync::Arc;
+use tokio::time::Duration;
+
+use super::metrics::ConsoleMetrics;
+use super::Console;
+use super::Node;
+use crate::settings::Settings;
+
+/// Step of a collect run an error occurred in.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum Stage {
+    Login,
+    Devices,
+    Compliance,
+    Netbox,
+}
+
+impl Stage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stage::Login => "login",
+            Stage::Devices => "devices",
+            Stage::Compliance => "compliance",
+            Stage::Netbox => "netbox",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CollectError {
+    pub stage: Stage,
+    pub message: String,
+}
+
+impl CollectError {
+    pub fn new(stage: Stage, error: impl fmt::Display) -> CollectError {
+        CollectError { stage, message: error.to_string() }
+    }
+}
+
+impl fmt::Display for CollectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} failed: {}", self.stage.as_str(), self.message)
+    }
+}
+
+#[async_trait]
+pub trait Collector: Send + Sync + std::fmt::Debug {
+    /// settings of the console the collector talks to
+    fn console(&self) -> &Console;
+
+    /// name of the console, used as the `console` label on exported nodes
+    fn name(&self) -> &str {
+        &self.console().name
+    }
+
+    /// url of the console, used as the `console_host` label on exported nodes
+    fn host(&self) -> &str {
+        self.console().host.as_str()
+    }
+
+    /// vendor of the hardware managed by the console (dell, hpe, lenovo)
+    fn vendor(&self) -> &'static str;
+
+    /// netbox query parameters selecting the devices the console should manage
+    fn netbox_query(&self) -> String {
+        self.console().netbox_query()
+    }
+
+    /// time to wait between two collect runs
+    fn interval(&self) -> Duration;
+
+    /// fetches all devices known by the console once. Errors which leave the
+    /// node list incomplete but usable (e.g. a failed compliance check) are
+    /// pushed to `errors`, errors which make the node list unusable are returned.
+    async fn collect(&self, errors: &mut Vec<CollectError>) -> Result<Vec<Node>, CollectError>;
+}
+
+/// Creates the collectors of a vendor module, one per configured console of the vendor.
+pub type Collectors = fn(&Settings, Duration, &ConsoleMetrics) -> Vec<Arc<dyn Collector>>;
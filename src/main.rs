@@ -9,6 +9,9 @@ use std::sync::Mutex;
 mod exporter;
 mod settings;
 mod netbox;
+#[cfg(test)]
+mod testing;
+use exporter::metrics::ConsoleMetrics;
 use exporter::Exporter;
 use settings::Settings;
 
@@ -35,6 +38,7 @@ impl Metrics {
 
 pub struct AppState {
     pub registry: Registry,
+    pub metrics: ConsoleMetrics,
 }
 
 #[get("/metrics")]
@@ -45,7 +49,10 @@ pub async fn metrics_handler(
     let state = state.lock().unwrap();
     let mut body = String::new();
     let mut content: &str = "text/plain; version=1.0.0; charset=utf-8";
-    encode(&mut body, &state.registry).unwrap();
+    {
+        let _guard = state.metrics.read();
+        encode(&mut body, &state.registry).unwrap();
+    }
     if let Some(content_type) = get_content_type(&req) {
         if content_type.contains("openmetrics-text") {
             content = "application/openmetrics-text; version=1.0.0; charset=utf-8";
@@ -75,16 +82,12 @@ async fn main() -> std::io::Result<()> {
         requests: Family::default(),
     });
 
+    let mut exp = Exporter::new(s);
     let mut state = AppState {
         registry: Registry::default(),
+        metrics: exp.metrics.clone(),
     };
-
-    let mut exp = Exporter::new(s);
-    state.registry.register(
-        "hardware_consoles",
-        "exporter for hardware consoles (dell, hpe, lenovo)",
-        exp.metrics.clone(),
-    );
+    exp.metrics.register(&mut state.registry);
     let state = web::Data::new(Mutex::new(state));
     actix_web::rt::spawn(async move { exp.run().await });
 
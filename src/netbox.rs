@@ -1,16 +1,94 @@
 use url::Url;
 use serde::{Deserialize, Serialize};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+use std::time::{Duration as StdDuration, SystemTime};
+use tokio::sync::{Mutex, RwLock};
+use tokio::time::Duration;
 use reqwest::Client;
-use reqwest::header::{ACCEPT, CONTENT_TYPE};
+use reqwest::header::{HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_TYPE};
+
+use crate::settings::{NetboxLabel, NetboxSettings};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct NetboxDevice {
-    pub id: u16,
+pub struct NestedObject {
+    pub id: u32,
     pub name: String,
+    pub slug: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Status {
+    pub value: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Tag {
+    pub name: String,
+    pub slug: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NetboxDevice {
+    pub id: u64,
+    pub name: String,
+    pub site: Option<NestedObject>,
+    pub location: Option<NestedObject>,
+    pub rack: Option<NestedObject>,
+    pub position: Option<f64>,
+    pub tenant: Option<NestedObject>,
+    pub role: Option<NestedObject>,
+    /// role before netbox 4.0, 3.6 and 3.7 return it next to `role`
+    pub device_role: Option<NestedObject>,
+    pub status: Option<Status>,
+    pub platform: Option<NestedObject>,
+    #[serde(default)]
+    pub tags: Vec<Tag>,
+    #[serde(default)]
+    pub serial: String,
+    pub asset_tag: Option<String>,
+    #[serde(default)]
+    pub custom_fields: HashMap<String, serde_json::Value>,
+    /// the configured netbox labels, set after fetching the device
+    #[serde(skip)]
+    pub labels: Vec<(String, String)>,
+    /// console named by the configured console field, set after fetching the device
+    #[serde(skip)]
+    pub expected_console: Option<String>,
+}
+
+impl NetboxDevice {
+    pub fn custom_field(&self, name: &str) -> Option<&str> {
+        self.custom_fields.get(name).and_then(|v| v.as_str())
+    }
+
+    pub fn site_name(&self) -> String {
+        self.label(NetboxLabel::Site)
+    }
+
+    pub fn label(&self, label: NetboxLabel) -> String {
+        let name = |o: &Option<NestedObject>| o.as_ref().map(|o| o.name.to_string()).unwrap_or_default();
+        match label {
+            NetboxLabel::Site => name(&self.site),
+            NetboxLabel::Location => name(&self.location),
+            NetboxLabel::Rack => name(&self.rack),
+            NetboxLabel::Position => self.position.map(|p| p.to_string()).unwrap_or_default(),
+            NetboxLabel::Tenant => name(&self.tenant),
+            NetboxLabel::Role => name(if self.role.is_some() { &self.role } else { &self.device_role }),
+            NetboxLabel::Status => self.status.as_ref().map(|s| s.value.to_string()).unwrap_or_default(),
+            NetboxLabel::Platform => name(&self.platform),
+            NetboxLabel::Tags => self.tags.iter().map(|t| t.slug.to_string()).collect::<Vec<String>>().join(","),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct NetboxDeviceList {
+    /// number of devices matching the query, independent of the page size
+    pub count: usize,
+    pub next: Option<String>,
     pub results: Vec<NetboxDevice>,
 }
 
@@ -18,22 +96,32 @@ pub struct NetboxDeviceList {
 pub struct Netbox {
     url: Url,
     query: String,
+    authorization: Option<HeaderValue>,
+    page_size: u32,
+    labels: Vec<NetboxLabel>,
+    console_field: Option<String>,
 }
 
 
 impl Netbox {
-    pub fn new(url: Url, query: String) -> Netbox {
+    pub fn new(url: Url, query: String, settings: &NetboxSettings) -> Netbox {
         Netbox {
             url: url,
             query: query,
+            authorization: settings.authorization.clone(),
+            page_size: settings.page_size,
+            labels: settings.labels.clone(),
+            console_field: settings.console_field.clone(),
         }
     }
 
-    pub async fn get_devices_by_manufacturer(&self, manufacturer: String) -> reqwest::Result<Vec<NetboxDevice>> {
+    /// Returns all devices matching the global query and `filter`, following the `next`
+    /// links of the result pages. `count` of the returned list is the count netbox reported.
+    pub async fn get_devices(&self, filter: &str) -> reqwest::Result<NetboxDeviceList> {
         let mut host = self.url.clone();
         let query = self.query.to_owned();
         host.set_path("/api/dcim/devices/");
-        host.set_query(Some(format!("{query}&limit=2000&manufacturer={}", manufacturer).as_str()));
+        host.set_query(Some(format!("{query}&limit={}&{}", self.page_size, filter).as_str()));
         let client = match Client::builder().danger_accept_invalid_certs(true).build() {
             Ok(client) => client,
             Err(error) => panic!("error creating reqwest client: {:?}", error),
@@ -41,13 +129,257 @@ impl Netbox {
         let mut header_map = reqwest::header::HeaderMap::new();
         header_map.insert(CONTENT_TYPE, "application/json".parse().unwrap());
         header_map.insert(ACCEPT, "application/json".parse().unwrap());
-        let result =  client.request(reqwest::Method::GET, host).headers(header_map)
-            .send()
-            .await?
-            .error_for_status()?
-            .json::<NetboxDeviceList>()
-            .await?;
+        if let Some(authorization) = &self.authorization {
+            header_map.insert(AUTHORIZATION, authorization.clone());
+        }
+        let mut devices = NetboxDeviceList::default();
+        let mut next = Some(host.to_string());
+        while let Some(url) = next {
+            let page = client.request(reqwest::Method::GET, url).headers(header_map.clone())
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<NetboxDeviceList>()
+                .await?;
+            devices.count = page.count;
+            // an empty page ends the walk, even if netbox links a next one
+            next = page.next.filter(|_| !page.results.is_empty());
+            devices.results.extend(page.results);
+        }
+        self.annotate(&mut devices);
+
+        Ok(devices)
+    }
+
+    /// Sets the configured labels and the expected console on all devices of the list.
+    fn annotate(&self, devices: &mut NetboxDeviceList) {
+        for device in devices.results.iter_mut() {
+            device.labels = self.labels.iter()
+                .map(|l| (l.as_str().to_string(), device.label(*l)))
+                .collect();
+            device.expected_console = self.console_field.as_ref()
+                .and_then(|f| device.custom_field(f))
+                .filter(|c| !c.is_empty())
+                .map(|c| c.to_string());
+        }
+    }
+}
+
+/// Device inventory shared by all collectors, so netbox is queried once per
+/// console filter and refresh interval instead of once per collector and cycle.
+/// Entries are served until they are replaced by a successful refresh, thus a
+/// netbox outage leaves the last fetched devices in place. With a `cache_file`
+/// they are persisted and served after a restart until netbox is back.
+#[derive(Debug, Clone)]
+pub struct Inventory {
+    netbox: Netbox,
+    interval: Duration,
+    cache_file: Option<String>,
+    entries: Arc<RwLock<HashMap<String, InventoryEntry>>>,
+    /// last fetch error of each filter, cleared once a fetch succeeds again
+    failures: Arc<RwLock<HashMap<String, String>>>,
+    /// serializes fetches, so collectors starting together query netbox once
+    fetching: Arc<Mutex<()>>,
+}
+
+/// Devices of one console filter as fetched at `fetched_at`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InventoryEntry {
+    pub devices: Arc<NetboxDeviceList>,
+    pub fetched_at: SystemTime,
+}
+
+impl InventoryEntry {
+    pub fn age(&self) -> StdDuration {
+        self.fetched_at.elapsed().unwrap_or_default()
+    }
+}
 
-        Ok(result.results)
+impl Inventory {
+    pub fn new(netbox: Netbox, interval: Duration, cache_file: Option<String>) -> Inventory {
+        let mut entries = HashMap::new();
+        if let Some(path) = &cache_file {
+            match load_entries(path) {
+                Ok(loaded) => {
+                    info!("loaded netbox inventory of {} filters from {}", loaded.len(), path);
+                    entries = loaded;
+                }
+                Err(e) => warn!("no netbox inventory loaded from {}: {}", path, e),
+            }
+        }
+        // annotations are not persisted, the configured ones may have changed since
+        for entry in entries.values_mut() {
+            netbox.annotate(Arc::make_mut(&mut entry.devices));
+        }
+        Inventory {
+            netbox,
+            interval,
+            cache_file,
+            entries: Arc::new(RwLock::new(entries)),
+            failures: Arc::new(RwLock::new(HashMap::new())),
+            fetching: Arc::new(Mutex::new(())),
+        }
     }
-}
\ No newline at end of file
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Returns the cached devices of the filter, even if they are stale.
+    /// Netbox is only queried if the filter was never fetched successfully.
+    pub async fn devices(&self, filter: &str) -> reqwest::Result<InventoryEntry> {
+        if let Some(entry) = self.entries.read().await.get(filter) {
+            return Ok(entry.clone());
+        }
+        let _fetching = self.fetching.lock().await;
+        // another collector may have fetched them while waiting for the lock
+        if let Some(entry) = self.entries.read().await.get(filter) {
+            return Ok(entry.clone());
+        }
+        self.fetch(filter).await
+    }
+
+    /// Fetches the devices of the filter. On errors the cached entry is kept.
+    pub async fn refresh(&self, filter: &str) -> reqwest::Result<InventoryEntry> {
+        let _fetching = self.fetching.lock().await;
+        self.fetch(filter).await
+    }
+
+    /// Returns why the last fetch of the filter failed, if it did.
+    pub async fn failure(&self, filter: &str) -> Option<String> {
+        self.failures.read().await.get(filter).cloned()
+    }
+
+    async fn fetch(&self, filter: &str) -> reqwest::Result<InventoryEntry> {
+        let devices = match self.netbox.get_devices(filter).await {
+            Ok(devices) => devices,
+            Err(e) => {
+                self.failures.write().await.insert(filter.to_string(), e.to_string());
+                return Err(e);
+            }
+        };
+        self.failures.write().await.remove(filter);
+        let entry = InventoryEntry {
+            devices: Arc::new(devices),
+            fetched_at: SystemTime::now(),
+        };
+        let mut entries = self.entries.write().await;
+        entries.insert(filter.to_string(), entry.clone());
+        if let Some(path) = &self.cache_file {
+            if let Err(e) = save_entries(path, &entries) {
+                warn!("persisting netbox inventory to {}: {}", path, e);
+            }
+        }
+        Ok(entry)
+    }
+}
+
+fn load_entries(path: &str) -> Result<HashMap<String, InventoryEntry>, Box<dyn std::error::Error>> {
+    Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+}
+
+/// Writes to a temporary file first, so a crash never leaves a truncated inventory behind.
+fn save_entries(path: &str, entries: &HashMap<String, InventoryEntry>) -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, serde_json::to_vec(entries)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockServer;
+
+    fn device(id: u64) -> serde_json::Value {
+        serde_json::json!({"id": id, "name": format!("node{:03}", id)})
+    }
+
+    const DEVICES: &str = "/api/dcim/devices/?role=server&limit=1000&manufacturer=dell";
+
+    fn inventory(server: &MockServer, settings: &NetboxSettings, cache_file: Option<String>) -> Inventory {
+        let netbox = Netbox::new(server.url.clone(), "role=server".to_string(), settings);
+        Inventory::new(netbox, Duration::from_secs(60), cache_file)
+    }
+
+    #[actix_web::test]
+    async fn get_devices_follows_next_links() {
+        let server = MockServer::start();
+        let settings = NetboxSettings { page_size: 1, ..Default::default() };
+        let netbox = Netbox::new(server.url.clone(), "role=server".to_string(), &settings);
+        server.route(
+            "/api/dcim/devices/?role=server&limit=1&manufacturer=dell",
+            serde_json::json!({"count": 3, "next": server.link("/api/dcim/devices/?page=2"), "results": [device(1)]}),
+        );
+        server.route(
+            "/api/dcim/devices/?page=2",
+            serde_json::json!({"count": 3, "next": server.link("/api/dcim/devices/?page=3"), "results": [device(2)]}),
+        );
+        // an empty page ends the walk, even if it links another one
+        server.route(
+            "/api/dcim/devices/?page=3",
+            serde_json::json!({"count": 3, "next": server.link("/api/dcim/devices/?page=4"), "results": []}),
+        );
+
+        let devices = netbox.get_devices("manufacturer=dell").await.unwrap();
+        assert_eq!(devices.count, 3);
+        assert_eq!(devices.results.iter().map(|d| d.id).collect::<Vec<u64>>(), vec![1, 2]);
+        assert_eq!(server.hits("/api/dcim/devices/?page=4"), 0);
+    }
+
+    #[actix_web::test]
+    async fn inventory_shares_fetched_devices() {
+        let server = MockServer::start();
+        let inventory = inventory(&server, &NetboxSettings::default(), None);
+        server.route(DEVICES, serde_json::json!({"count": 1, "next": null, "results": [device(1)]}));
+        inventory.devices("manufacturer=dell").await.unwrap();
+        inventory.clone().devices("manufacturer=dell").await.unwrap();
+        assert_eq!(server.hits(DEVICES), 1);
+
+        server.route(DEVICES, serde_json::json!({"count": 2, "next": null, "results": [device(1), device(2)]}));
+        inventory.refresh("manufacturer=dell").await.unwrap();
+        assert_eq!(server.hits(DEVICES), 2);
+        assert_eq!(inventory.devices("manufacturer=dell").await.unwrap().devices.results.len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn inventory_keeps_devices_of_failed_refreshes() {
+        let server = MockServer::start();
+        let inventory = inventory(&server, &NetboxSettings::default(), None);
+        server.route(DEVICES, serde_json::json!({"count": 1, "next": null, "results": [device(1)]}));
+        inventory.devices("manufacturer=dell").await.unwrap();
+
+        server.route_status(DEVICES, 503, serde_json::json!({}));
+        assert!(inventory.refresh("manufacturer=dell").await.is_err());
+        assert!(inventory.failure("manufacturer=dell").await.is_some());
+        assert_eq!(inventory.devices("manufacturer=dell").await.unwrap().devices.results.len(), 1);
+
+        server.route(DEVICES, serde_json::json!({"count": 1, "next": null, "results": [device(1)]}));
+        inventory.refresh("manufacturer=dell").await.unwrap();
+        assert!(inventory.failure("manufacturer=dell").await.is_none());
+    }
+
+    #[actix_web::test]
+    async fn inventory_is_loaded_from_its_cache_file() {
+        let path = std::env::temp_dir().join(format!("netbox-inventory-{}.json", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let server = MockServer::start();
+        server.route(DEVICES, serde_json::json!({"count": 1, "next": null, "results": [device(1)]}));
+        inventory(&server, &NetboxSettings::default(), Some(path.clone()))
+            .devices("manufacturer=dell")
+            .await
+            .unwrap();
+
+        // netbox is down after a restart, the labels configured since are applied anyway
+        let down = MockServer::start();
+        let settings = NetboxSettings { labels: vec![NetboxLabel::Site], ..Default::default() };
+        let entry = inventory(&down, &settings, Some(path.clone()))
+            .devices("manufacturer=dell")
+            .await
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(down.hits(DEVICES), 0);
+        assert_eq!(entry.devices.results[0].id, 1);
+        assert_eq!(entry.devices.results[0].labels, vec![("site".to_string(), "".to_string())]);
+    }
+}
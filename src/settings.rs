@@ -1,38 +1,359 @@
 use config::{Config, ConfigError, Environment, File};
+use log::warn;
+use regex::Regex;
+use reqwest::header::HeaderValue;
 use serde::{de::Error, Deserialize, Deserializer};
+use std::collections::{BTreeMap, HashSet};
 use std::env;
-use url::Url;
+use std::fs;
+use url::{form_urlencoded, Url};
 
+/// Names of the consoles configured with a vendor's `host`.
+pub const DELL_CONSOLE: &str = "openmanage";
+pub const LENOVO_CONSOLE: &str = "lxca";
+pub const HPE_CONSOLE: &str = "oneview";
+
+/// Ways to match a netbox device to a console node.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchStrategy {
+    /// netbox and console name are equal, ignoring case
+    Hostname,
+    /// netbox and console name are equal after applying the `name_rules` to both
+    NormalizedHostname,
+    Serial,
+    AssetTag,
+    /// node system uuid equals the netbox custom field `uuid_field`, not reported by dell consoles
+    Uuid,
+}
+
+/// A step of the normalization applied to netbox and console names, the normalized
+/// console name is exported as `device_name`.
 #[derive(Debug, Deserialize, Clone)]
-#[allow(unused)]
+#[serde(rename_all = "snake_case")]
+pub enum NameRule {
+    /// cut the name at the first dot
+    StripDomain,
+    Lowercase,
+    /// replace all matches of `pattern`, `replace` may reference capture groups
+    Regex {
+        #[serde(deserialize_with = "de_regex")]
+        pattern: Regex,
+        replace: String,
+    },
+    /// replace a leading `from` with `to`
+    Prefix { from: String, to: String },
+    /// replace a trailing `from` with `to`
+    Suffix { from: String, to: String },
+}
+
+/// Netbox filters selecting the devices a console is expected to manage, appended
+/// to the global `query`. Netbox ANDs different fields and ORs repeated values of a
+/// field, also if the field is part of the global query.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct NetboxFilter {
+    /// manufacturer slug, defaults to `manufacturer_name`
+    pub manufacturer: Option<String>,
+    #[serde(default)]
+    pub site: Vec<String>,
+    #[serde(default)]
+    pub tag: Vec<String>,
+    #[serde(default)]
+    pub role: Vec<String>,
+    /// custom field values by field name, sent as `cf_<name>=<value>`
+    #[serde(default)]
+    pub custom_fields: BTreeMap<String, String>,
+}
+
+/// A single console appliance, resolved from its vendor section.
+#[derive(Debug, Clone)]
 pub struct Console {
-    #[serde(deserialize_with = "de_url")]
+    pub name: String,
     pub host: Url,
     pub domain: Option<String>,
     pub username: String,
     pub password: Option<String>,
+    pub policy_name: String,
+    /// dell firmware baselines, a node is evaluated against the first one targeting it
+    pub baselines: Vec<String>,
+    pub job_timeout_in_sec: u64,
+    pub manufacturer_name: String,
+    pub match_strategies: Vec<MatchStrategy>,
+    pub uuid_field: String,
+    pub name_rules: Vec<NameRule>,
+    pub netbox_filter: NetboxFilter,
+}
+
+impl Console {
+    /// Netbox query parameters selecting the devices of the console.
+    pub fn netbox_query(&self) -> String {
+        let filter = &self.netbox_filter;
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        query.append_pair("manufacturer", filter.manufacturer.as_deref().unwrap_or(&self.manufacturer_name));
+        for (key, values) in [("site", &filter.site), ("tag", &filter.tag), ("role", &filter.role)] {
+            for value in values {
+                query.append_pair(key, value);
+            }
+        }
+        for (name, value) in filter.custom_fields.iter() {
+            query.append_pair(&format!("cf_{}", name), value);
+        }
+        query.finish()
+    }
+}
+
+/// Settings shared by all consoles of a vendor. A single console can be configured
+/// with `host`, several with `instances`, which may override the shared settings.
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+pub struct Vendor {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default, deserialize_with = "de_opt_url")]
+    pub host: Option<Url>,
+    pub domain: Option<String>,
+    pub username: String,
+    pub password: Option<String>,
     #[serde(default = "default_policy_name")]
     pub policy_name: String,
+    /// firmware baselines evaluated in order (dell), defaults to `policy_name`
+    #[serde(default)]
+    pub baselines: Vec<String>,
+    /// time to wait for the compliance check of the policy to complete (dell)
+    #[serde(default = "default_job_timeout")]
+    pub job_timeout_in_sec: u64,
     pub manufacturer_name: String,
+    /// strategies tried in order until one finds a match
+    #[serde(default = "default_match_strategies")]
+    pub match_strategies: Vec<MatchStrategy>,
+    /// netbox custom field holding the system uuid, used by the `uuid` strategy
+    #[serde(default = "default_uuid_field")]
+    pub uuid_field: String,
+    #[serde(default = "default_name_rules")]
+    pub name_rules: Vec<NameRule>,
+    #[serde(default)]
+    pub netbox_filter: NetboxFilter,
+    #[serde(default)]
+    pub instances: Vec<Instance>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+pub struct Instance {
+    pub name: String,
+    #[serde(deserialize_with = "de_url")]
+    pub host: Url,
+    pub domain: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub policy_name: Option<String>,
+    pub baselines: Option<Vec<String>>,
+    pub job_timeout_in_sec: Option<u64>,
+    pub match_strategies: Option<Vec<MatchStrategy>>,
+    pub uuid_field: Option<String>,
+    pub name_rules: Option<Vec<NameRule>>,
+    pub netbox_filter: Option<NetboxFilter>,
+}
+
+impl Vendor {
+    /// Returns one console per configured instance. `default_name` names the console
+    /// configured via `host`. A disabled vendor has no consoles.
+    pub fn consoles(&self, default_name: &str) -> Vec<Console> {
+        let mut consoles = vec![];
+        if !self.enabled {
+            return consoles;
+        }
+        if let Some(host) = &self.host {
+            consoles.push(Console {
+                name: default_name.to_string(),
+                host: host.clone(),
+                domain: self.domain.clone(),
+                username: self.username.clone(),
+                password: self.password.clone(),
+                policy_name: self.policy_name.clone(),
+                baselines: baselines(&self.baselines, &self.policy_name),
+                job_timeout_in_sec: self.job_timeout_in_sec,
+                manufacturer_name: self.manufacturer_name.clone(),
+                match_strategies: self.match_strategies.clone(),
+                uuid_field: self.uuid_field.clone(),
+                name_rules: self.name_rules.clone(),
+                netbox_filter: self.netbox_filter.clone(),
+            });
+        }
+        for i in self.instances.iter() {
+            let policy_name = i.policy_name.clone().unwrap_or_else(|| self.policy_name.clone());
+            consoles.push(Console {
+                name: i.name.clone(),
+                host: i.host.clone(),
+                domain: i.domain.clone().or_else(|| self.domain.clone()),
+                username: i.username.clone().unwrap_or_else(|| self.username.clone()),
+                password: i.password.clone().or_else(|| self.password.clone()),
+                baselines: baselines(i.baselines.as_ref().unwrap_or(&self.baselines), &policy_name),
+                policy_name,
+                job_timeout_in_sec: i.job_timeout_in_sec.unwrap_or(self.job_timeout_in_sec),
+                manufacturer_name: self.manufacturer_name.clone(),
+                match_strategies: i.match_strategies.clone().unwrap_or_else(|| self.match_strategies.clone()),
+                uuid_field: i.uuid_field.clone().unwrap_or_else(|| self.uuid_field.clone()),
+                name_rules: i.name_rules.clone().unwrap_or_else(|| self.name_rules.clone()),
+                netbox_filter: i.netbox_filter.clone().unwrap_or_else(|| self.netbox_filter.clone()),
+            });
+        }
+        consoles
+    }
+}
+
+/// Falls back to the policy name as only baseline if no baselines are configured.
+fn baselines(baselines: &[String], policy_name: &str) -> Vec<String> {
+    match (baselines.is_empty(), policy_name.is_empty()) {
+        (false, _) => baselines.to_vec(),
+        (true, false) => vec![policy_name.to_string()],
+        (true, true) => vec![],
+    }
+}
+
+/// Netbox device fields which can be attached as labels to `node_info`.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NetboxLabel {
+    Site,
+    Location,
+    Rack,
+    Position,
+    Tenant,
+    Role,
+    Status,
+    Platform,
+    /// comma separated tag slugs
+    Tags,
+}
+
+impl NetboxLabel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NetboxLabel::Site => "site",
+            NetboxLabel::Location => "location",
+            NetboxLabel::Rack => "rack",
+            NetboxLabel::Position => "position",
+            NetboxLabel::Tenant => "tenant",
+            NetboxLabel::Role => "role",
+            NetboxLabel::Status => "status",
+            NetboxLabel::Platform => "platform",
+            NetboxLabel::Tags => "tags",
+        }
+    }
+}
+
+/// Netbox client settings. The token can also be set with `EXPORTER_NETBOX_TOKEN`
+/// or read from `token_file`, e.g. a mounted secret.
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+pub struct NetboxSettings {
+    pub token: Option<String>,
+    pub token_file: Option<String>,
+    /// devices requested per page, netbox caps this at its `MAX_PAGE_SIZE`
+    #[serde(default = "default_page_size")]
+    pub page_size: u32,
+    /// device fields attached as labels to `node_info`
+    #[serde(default)]
+    pub labels: Vec<NetboxLabel>,
+    /// interval the shared device inventory is refreshed in, defaults to `interval_in_min`
+    pub refresh_interval_in_min: Option<u64>,
+    /// file the inventory is persisted to, so it survives restarts during netbox outages
+    pub cache_file: Option<String>,
+    /// custom field naming the console a device must be found on. Devices naming another
+    /// configured console are not reported as unmanaged, if found anyway they are reported
+    /// as misassigned. Devices naming an unknown console are logged and reported as unmanaged if missing.
+    pub console_field: Option<String>,
+    /// `Authorization` header of the token, built once the settings are loaded
+    #[serde(skip)]
+    pub authorization: Option<HeaderValue>,
+}
+
+impl Default for NetboxSettings {
+    fn default() -> NetboxSettings {
+        NetboxSettings {
+            token: None,
+            token_file: None,
+            page_size: default_page_size(),
+            labels: vec![],
+            refresh_interval_in_min: None,
+            cache_file: None,
+            console_field: None,
+            authorization: None,
+        }
+    }
+}
+
+impl NetboxSettings {
+    /// Returns the configured token, a token file takes precedence over `token`.
+    /// Surrounding whitespace is removed, an empty token is no token.
+    pub fn token(&self) -> Result<Option<String>, ConfigError> {
+        let token = match &self.token_file {
+            Some(path) => fs::read_to_string(path)
+                .map(Some)
+                .map_err(|e| ConfigError::Message(format!("reading netbox token file {}: {}", path, e)))?,
+            None => self.token.clone(),
+        };
+        Ok(token.map(|t| t.trim().to_string()).filter(|t| !t.is_empty()))
+    }
+
+    /// Builds the `Authorization` header of the token, fails if the token can't be sent in a header.
+    pub fn authorization(&self) -> Result<Option<HeaderValue>, ConfigError> {
+        self.token
+            .as_ref()
+            .map(|token| {
+                let mut value = HeaderValue::from_str(&format!("Token {}", token))
+                    .map_err(|e| ConfigError::Message(format!("invalid netbox token: {}", e)))?;
+                value.set_sensitive(true);
+                Ok(value)
+            })
+            .transpose()
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
 #[allow(unused)]
 pub struct Settings {
-    pub dell: Console,
-    pub lenovo: Console,
-    pub hpe: Console,
+    pub dell: Option<Vendor>,
+    pub lenovo: Option<Vendor>,
+    pub hpe: Option<Vendor>,
     #[serde(default = "default_interval")]
     pub interval_in_min: u64,
     #[serde(deserialize_with = "de_url")]
     pub netbox_url: Url,
     pub query: String,
+    #[serde(default)]
+    pub netbox: NetboxSettings,
 }
 
 fn default_interval() -> u64 {
     30
 }
 
+fn default_page_size() -> u32 {
+    1000
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_match_strategies() -> Vec<MatchStrategy> {
+    vec![MatchStrategy::Serial, MatchStrategy::Hostname, MatchStrategy::NormalizedHostname]
+}
+
+fn default_uuid_field() -> String {
+    "uuid".to_string()
+}
+
+fn default_name_rules() -> Vec<NameRule> {
+    vec![NameRule::StripDomain, NameRule::Lowercase]
+}
+
+fn default_job_timeout() -> u64 {
+    600
+}
+
 fn default_policy_name() -> String {
     "".to_string()
 }
@@ -45,6 +366,24 @@ where
     Url::parse(&buf).map_err(D::Error::custom)
 }
 
+fn de_regex<'de, D>(deserializer: D) -> Result<Regex, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let buf = String::deserialize(deserializer)?;
+    Regex::new(&buf).map_err(D::Error::custom)
+}
+
+fn de_opt_url<'de, D>(deserializer: D) -> Result<Option<Url>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(buf) => Url::parse(&buf).map(Some).map_err(D::Error::custom),
+        None => Ok(None),
+    }
+}
+
 impl Settings {
     pub fn new() -> Result<Self, ConfigError> {
         let run_mode = env::var("RUN_MODE").unwrap_or_else(|_| "development".into());
@@ -56,6 +395,217 @@ impl Settings {
             .build()?;
 
         // You can deserialize (and thus freeze) the entire configuration as
-        s.try_deserialize()
+        let mut settings: Settings = s.try_deserialize()?;
+        settings.netbox.token = settings.netbox.token()?;
+        settings.netbox.authorization = settings.netbox.authorization()?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// Rejects consoles sharing a name, they would replace each other's series. Without
+    /// a console field consoles of a vendor need distinct netbox filters, otherwise each
+    /// reports the devices of the others as unmanaged.
+    fn validate(&self) -> Result<(), ConfigError> {
+        let vendors = [
+            ("dell", &self.dell, DELL_CONSOLE),
+            ("lenovo", &self.lenovo, LENOVO_CONSOLE),
+            ("hpe", &self.hpe, HPE_CONSOLE),
+        ];
+        let mut names = HashSet::new();
+        for (vendor, settings, default_name) in vendors {
+            let Some(settings) = settings else { continue };
+            if settings.enabled && settings.host.is_none() && settings.instances.is_empty() {
+                warn!("{} is enabled, but has neither a host nor instances", vendor);
+            }
+            let mut queries = HashSet::new();
+            for console in settings.consoles(default_name) {
+                if !names.insert(console.name.clone()) {
+                    return Err(ConfigError::Message(format!("console name {} is used more than once", console.name)));
+                }
+                if self.netbox.console_field.is_none() && !queries.insert(console.netbox_query()) {
+                    return Err(ConfigError::Message(format!(
+                        "{} console {} has the netbox filter of another {} console, set a netbox_filter or netbox.console_field",
+                        vendor, console.name, vendor
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vendor(json: serde_json::Value) -> Vendor {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn consoles_of_host_and_instances() {
+        let v = vendor(serde_json::json!({
+            "host": "https://ome.example.com",
+            "username": "admin",
+            "policy_name": "fw",
+            "manufacturer_name": "dell",
+            "instances": [
+                {"name": "ome-a", "host": "https://ome-a.example.com"},
+                {
+                    "name": "ome-b",
+                    "host": "https://ome-b.example.com",
+                    "username": "other",
+                    "policy_name": "fw-b",
+                    "match_strategies": ["asset_tag"],
+                },
+            ],
+        }));
+        let consoles = v.consoles("openmanage");
+        let names = consoles.iter().map(|c| c.name.as_str()).collect::<Vec<&str>>();
+        assert_eq!(names, vec!["openmanage", "ome-a", "ome-b"]);
+
+        assert_eq!(consoles[1].username, "admin");
+        assert_eq!(consoles[1].baselines, vec!["fw"]);
+        assert_eq!(consoles[1].match_strategies, default_match_strategies());
+
+        assert_eq!(consoles[2].username, "other");
+        assert_eq!(consoles[2].baselines, vec!["fw-b"]);
+        assert_eq!(consoles[2].match_strategies, vec![MatchStrategy::AssetTag]);
+    }
+
+    #[test]
+    fn duplicate_console_names_are_rejected() {
+        let settings = |instance: &str| -> Settings {
+            serde_json::from_value(serde_json::json!({
+                "netbox_url": "https://netbox.example.com",
+                "query": "role=server",
+                "dell": {
+                    "host": "https://ome.example.com",
+                    "username": "admin",
+                    "manufacturer_name": "dell",
+                    "instances": [{
+                        "name": instance,
+                        "host": "https://ome-a.example.com",
+                        "netbox_filter": {"site": ["a"]},
+                    }],
+                },
+                "lenovo": {
+                    "host": "https://lxca.example.com",
+                    "username": "admin",
+                    "manufacturer_name": "lenovo",
+                },
+            }))
+            .unwrap()
+        };
+        assert!(settings("ome-a").validate().is_ok());
+        assert!(settings(DELL_CONSOLE).validate().is_err());
+        assert!(settings(LENOVO_CONSOLE).validate().is_err());
+    }
+
+    #[test]
+    fn consoles_of_a_vendor_need_distinct_filters() {
+        let settings = |filter: serde_json::Value, netbox: serde_json::Value| -> Settings {
+            serde_json::from_value(serde_json::json!({
+                "netbox_url": "https://netbox.example.com",
+                "query": "role=server",
+                "netbox": netbox,
+                "dell": {
+                    "username": "admin",
+                    "manufacturer_name": "dell",
+                    "instances": [
+                        {"name": "ome-a", "host": "https://ome-a.example.com", "netbox_filter": {"site": ["a"]}},
+                        {"name": "ome-b", "host": "https://ome-b.example.com", "netbox_filter": filter},
+                    ],
+                },
+            }))
+            .unwrap()
+        };
+        assert!(settings(serde_json::json!({"site": ["b"]}), serde_json::json!({})).validate().is_ok());
+        assert!(settings(serde_json::json!({"site": ["a"]}), serde_json::json!({})).validate().is_err());
+        let console_field = serde_json::json!({"console_field": "console"});
+        assert!(settings(serde_json::json!({"site": ["a"]}), console_field).validate().is_ok());
+    }
+
+    #[test]
+    fn disabled_vendor_has_no_consoles() {
+        let v = vendor(serde_json::json!({
+            "enabled": false,
+            "host": "https://ome.example.com",
+            "username": "admin",
+            "manufacturer_name": "dell",
+        }));
+        assert!(v.consoles("openmanage").is_empty());
+    }
+
+    #[test]
+    fn vendors_are_optional() {
+        let s: Settings = serde_json::from_value(serde_json::json!({
+            "netbox_url": "https://netbox.example.com",
+            "query": "role=server",
+            "hpe": {
+                "host": "https://oneview.example.com",
+                "username": "admin",
+                "manufacturer_name": "hpe",
+            },
+        }))
+        .unwrap();
+        assert!(s.dell.is_none());
+        assert!(s.lenovo.is_none());
+        assert!(s.validate().is_ok());
+    }
+
+    #[test]
+    fn netbox_token_is_trimmed_and_checked() {
+        let netbox = |token: &str| NetboxSettings { token: Some(token.to_string()), ..Default::default() };
+        let settings = NetboxSettings { token: netbox(" abc\n").token().unwrap(), ..Default::default() };
+        assert_eq!(settings.token.as_deref(), Some("abc"));
+        assert_eq!(settings.authorization().unwrap().unwrap(), "Token abc");
+        assert_eq!(netbox("\n").token().unwrap(), None);
+        assert!(netbox("a\nb").authorization().is_err());
+    }
+
+    #[test]
+    fn netbox_query_of_filter() {
+        let v = vendor(serde_json::json!({
+            "host": "https://ome.example.com",
+            "username": "admin",
+            "manufacturer_name": "dell",
+            "netbox_filter": {
+                "site": ["bb1", "bb2"],
+                "tag": ["managed-by-ome"],
+                "custom_fields": {"owner": "team a"},
+            },
+        }));
+        let console = &v.consoles("openmanage")[0];
+        assert_eq!(
+            console.netbox_query(),
+            "manufacturer=dell&site=bb1&site=bb2&tag=managed-by-ome&cf_owner=team+a"
+        );
+    }
+
+    #[test]
+    fn netbox_query_manufacturer_override() {
+        let v = vendor(serde_json::json!({
+            "host": "https://ome.example.com",
+            "username": "admin",
+            "manufacturer_name": "dell",
+            "netbox_filter": {"manufacturer": "dell-emc", "role": ["server"]},
+        }));
+        assert_eq!(v.consoles("openmanage")[0].netbox_query(), "manufacturer=dell-emc&role=server");
+    }
+
+    #[test]
+    fn instance_netbox_filter_replaces_the_vendor_one() {
+        let v = vendor(serde_json::json!({
+            "username": "admin",
+            "manufacturer_name": "dell",
+            "netbox_filter": {"site": ["bb1"]},
+            "instances": [
+                {"name": "ome-a", "host": "https://ome-a.example.com"},
+                {"name": "ome-b", "host": "https://ome-b.example.com", "netbox_filter": {"site": ["bb2"]}},
+            ],
+        }));
+        let queries = v.consoles("openmanage").iter().map(|c| c.netbox_query()).collect::<Vec<String>>();
+        assert_eq!(queries, vec!["manufacturer=dell&site=bb1", "manufacturer=dell&site=bb2"]);
     }
 }
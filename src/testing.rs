@@ -0,0 +1,64 @@
+use actix_web::http::StatusCode;
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use url::Url;
+
+#[derive(Debug, Default)]
+struct Routes {
+    responses: HashMap<String, (u16, String)>,
+    hits: HashMap<String, usize>,
+}
+
+/// Http server answering requests with canned json by their path and query, for
+/// tests of the netbox and console clients. Unknown requests are answered with 404.
+#[derive(Debug, Clone)]
+pub struct MockServer {
+    pub url: Url,
+    routes: Arc<Mutex<Routes>>,
+}
+
+impl MockServer {
+    /// Starts the server on a free local port, must be called within an actix system.
+    pub fn start() -> MockServer {
+        let routes = Arc::new(Mutex::new(Routes::default()));
+        let data = web::Data::from(routes.clone());
+        let server = HttpServer::new(move || App::new().app_data(data.clone()).default_service(web::to(respond)))
+            .workers(1)
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+        let url = Url::parse(&format!("http://{}", server.addrs()[0])).unwrap();
+        actix_web::rt::spawn(server.run());
+        MockServer { url, routes }
+    }
+
+    pub fn route(&self, path: &str, body: serde_json::Value) {
+        self.route_status(path, 200, body);
+    }
+
+    pub fn route_status(&self, path: &str, status: u16, body: serde_json::Value) {
+        self.routes.lock().unwrap().responses.insert(path.to_string(), (status, body.to_string()));
+    }
+
+    /// Number of requests of `path`, whether it is routed or not.
+    pub fn hits(&self, path: &str) -> usize {
+        self.routes.lock().unwrap().hits.get(path).copied().unwrap_or_default()
+    }
+
+    /// Absolute url of `path` on the server.
+    pub fn link(&self, path: &str) -> String {
+        self.url.join(path).unwrap().to_string()
+    }
+}
+
+async fn respond(req: HttpRequest, routes: web::Data<Mutex<Routes>>) -> HttpResponse {
+    let path = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or_default().to_string();
+    let mut routes = routes.lock().unwrap();
+    *routes.hits.entry(path.clone()).or_default() += 1;
+    match routes.responses.get(&path) {
+        Some((status, body)) => HttpResponse::build(StatusCode::from_u16(*status).unwrap())
+            .content_type("application/json")
+            .body(body.clone()),
+        None => HttpResponse::NotFound().finish(),
+    }
+}